@@ -1,13 +1,20 @@
 use std::{collections::HashMap, sync::{Arc, Mutex}};
 
-use game::{self, Game, GameListing, GameSettings, PlayerInfo, PlayerRequest, PlayerRole};
+use game::{self, BotPolicy, FacilitatorView, Game, GameCreated, GameListing, GameSettings, GameStatus, OrderLine, PlayerInfo, PlayerRequest, PlayerRole, SeatGrant, SessionJoin, SessionSeat, TeamResult};
 use ehttp::{fetch,Request};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{EventSource, MessageEvent};
+
+use crate::dashboard::{self, AdminAction};
+use crate::offline;
+use crate::setup;
 
 #[derive(PartialEq)]
 pub enum GameStyleChoice {
     NewSingleplayer,
     NewMultiplayer,
-    JoinMultiplayer
+    JoinMultiplayer,
+    Facilitate,
 }
 
 pub struct ClientApp {
@@ -20,6 +27,35 @@ pub struct ClientApp {
     player_info: Option<PlayerInfo>,
     new_game_settings: GameSettings,
     outgoing_request: u32,
+    managed_request: u32,
+    expedite_request: u32,
+    alternative_requests: Vec<u32>,
+    product_requests: Vec<u32>,
+    // Orders the server holds for this week, by role
+    committed: Arc<Mutex<HashMap<PlayerRole, PlayerRequest>>>,
+    // Proof of the seat we joined, sent along with everything we do in the game
+    seat_token: Arc<Mutex<Option<String>>>,
+    // Live updates for the game we are in or facilitating, along with the facilitator's token if any
+    events: Option<((i64, Option<String>), EventSource)>,
+    // The game being facilitated and the token for it, while the dashboard is open
+    facilitator: Option<(i64, String)>,
+    facilitator_view: Arc<Mutex<Option<FacilitatorView>>>,
+    facilitate_id: i64,
+    facilitate_token: String,
+    // Every team's results when facilitating a classroom session rather than a single game
+    session_results: Arc<Mutex<Option<Vec<TeamResult>>>>,
+    session_id: i64,
+    // A seat we are given once the server replies, from joining a session or a game we created
+    pending_seat: Arc<Mutex<Option<SessionSeat>>>,
+    // The seat chosen when starting a new game of either kind
+    chosen_role: PlayerRole,
+    // Single player runs the game here in the client, against bots in the other seats
+    offline: bool,
+    single_bots: HashMap<PlayerRole, BotPolicy>,
+    // The last game we created, whose id is the code others join it by
+    created: Arc<Mutex<Option<GameCreated>>>,
+    create_error: Arc<Mutex<Option<String>>>,
+    join_code: String,
 }
 
 impl Default for ClientApp {
@@ -44,8 +80,49 @@ impl Default for ClientApp {
                     (PlayerRole::Distributor, None),
                     (PlayerRole::Manufacturer, None),
                 ]),
+                vendor_managed: vec![],
+                chain: PlayerRole::ROLES.to_vec(),
+                order_cost: 0,
+                unit_cost: 0,
+                min_order: 0,
+                order_multiple: 0,
+                expedite: None,
+                alternative_suppliers: vec![],
+                production: Default::default(),
+                shelf_life: None,
+                spoilage_cost: 0,
+                demand: Default::default(),
+                products: vec![],
+                storage_capacity: 0,
+                overflow_cost: 0,
+                profit: None,
+                contracts: HashMap::new(),
+                emissions: Default::default(),
+                allocation: Default::default(),
+                turn_deadline: None,
+                bots: HashMap::new(),
             },
-            outgoing_request: 4
+            outgoing_request: 4,
+            managed_request: 4,
+            expedite_request: 0,
+            alternative_requests: vec![],
+            product_requests: vec![],
+            committed: Arc::new(Mutex::new(HashMap::new())),
+            seat_token: Arc::new(Mutex::new(None)),
+            events: None,
+            facilitator: None,
+            facilitator_view: Arc::new(Mutex::new(None)),
+            facilitate_id: 1,
+            facilitate_token: String::new(),
+            session_results: Arc::new(Mutex::new(None)),
+            session_id: 1,
+            pending_seat: Arc::new(Mutex::new(None)),
+            chosen_role: PlayerRole::Retailer,
+            offline: false,
+            single_bots: PlayerRole::ROLES.iter().map(|r| (*r, BotPolicy::BaseStock { target: 12 })).collect(),
+            created: Arc::new(Mutex::new(None)),
+            create_error: Arc::new(Mutex::new(None)),
+            join_code: String::new(),
         }
     }
 }
@@ -56,7 +133,196 @@ impl ClientApp {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
 
-        Default::default()
+        let mut app: ClientApp = Default::default();
+
+        // Pick up the seat we held before the page was reloaded
+        let session = cc.storage
+            .and_then(|s| s.get_string(SESSION_KEY))
+            .and_then(|s| serde_json::from_str::<(i64, PlayerInfo, String)>(&s).ok());
+        if let Some((game_id, player_info, token)) = session {
+            app.reclaim_seat(game_id, player_info, token, cc.egui_ctx.clone());
+        }
+        app
+    }
+
+    // Nothing is taken up until the server hands the seat back, so a seat that was lost is forgotten at the next save
+    fn reclaim_seat(&mut self, game_id: i64, player_info: PlayerInfo, token: String, ctx: egui::Context) {
+        let cloned_seat = self.pending_seat.clone();
+        let cloned_committed = self.committed.clone();
+        let role = player_info.role;
+        self.player_name = player_info.name;
+        let request = authorised(Request::post(format!("http://127.0.0.1:8000/reclaimseat/{}", game_id), vec![]), &Some(token));
+        fetch(request, move |response| {
+            // A seat that was freed in the meantime leaves us back at game selection
+            if let Some(grant) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<SeatGrant>().ok()) {
+                // Show what was already ordered this week, for our own role and any we order for
+                let token = Some(grant.token.clone());
+                let settings = &grant.game.settings;
+                for ordered in settings.chain.iter().copied().filter(|r| settings.ordering_role(*r) == role) {
+                    let cloned_committed = cloned_committed.clone();
+                    let url = format!("http://127.0.0.1:8000/committed/{}/{}", game_id, ordered as u32);
+                    fetch(authorised(Request::get(url), &token), move |response| {
+                        if let Some(committed) = response.ok().and_then(|r| r.json::<Option<PlayerRequest>>().ok()).flatten() {
+                            cloned_committed.lock().unwrap().insert(ordered, committed);
+                        }
+                    });
+                }
+                *cloned_seat.lock().unwrap() = Some(SessionSeat { game_id, role, token: grant.token, game: grant.game });
+            }
+            ctx.request_repaint();
+        });
+    }
+
+    // Keeps an event stream open for the current game and reloads the game whenever something happens in it
+    fn follow_game_events(&mut self, ctx: &egui::Context) {
+        let followed = match &self.facilitator {
+            Some((_, token)) if is_session_token(token) => None,
+            Some((id, token)) => Some((*id, Some(token.clone()))),
+            None => self.current_game_id.map(|id| (id, None)),
+        };
+        if self.events.as_ref().map(|(f, _)| f) == followed.as_ref() {
+            return
+        }
+        if let Some((_, source)) = self.events.take() {
+            source.close();
+        }
+        let Some((id, token)) = followed else { return };
+        let Ok(source) = EventSource::new(&format!("http://127.0.0.1:8000/events/{}", id)) else { return };
+
+        let cloned_game = self.current_game.clone();
+        let cloned_view = self.facilitator_view.clone();
+        let cloned_token = token.clone();
+        let ctx = ctx.clone();
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |_: MessageEvent| {
+            let cloned_game = cloned_game.clone();
+            let ctx = ctx.clone();
+            match &cloned_token {
+                Some(token) => Self::load_dashboard(&cloned_view, id, token, ctx),
+                None => fetch(Request::get(format!("http://127.0.0.1:8000/gamestate/{}", id)), move |response| {
+                    if let Some(game) = response.ok().and_then(|r| r.json::<Game>().ok()) {
+                        *cloned_game.lock().unwrap() = Some(game);
+                        ctx.request_repaint();
+                    }
+                }),
+            }
+        });
+        source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        // The handler lives as long as the page, closing the source is what stops it
+        on_message.forget();
+        self.events = Some(((id, token), source));
+    }
+
+    fn load_dashboard(view: &Arc<Mutex<Option<FacilitatorView>>>, id: i64, token: &str, ctx: egui::Context) {
+        let cloned_view = view.clone();
+        let request = authorised(Request::get(format!("http://127.0.0.1:8000/admin/{}/state", id)), &Some(token.to_owned()));
+        fetch(request, move |response| {
+            if let Some(v) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<FacilitatorView>().ok()) {
+                *cloned_view.lock().unwrap() = Some(v);
+                ctx.request_repaint();
+            }
+        });
+    }
+
+    fn load_session_results(results: &Arc<Mutex<Option<Vec<TeamResult>>>>, id: i64, token: &str, ctx: egui::Context) {
+        let cloned_results = results.clone();
+        let request = authorised(Request::get(format!("http://127.0.0.1:8000/session/{}/results", id)), &Some(token.to_owned()));
+        fetch(request, move |response| {
+            if let Some(r) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<Vec<TeamResult>>().ok()) {
+                *cloned_results.lock().unwrap() = Some(r);
+                ctx.request_repaint();
+            }
+        });
+    }
+
+    fn open_dashboard(&mut self, ctx: &egui::Context) {
+        self.facilitator = Some((self.facilitate_id, self.facilitate_token.clone()));
+        match is_session_token(&self.facilitate_token) {
+            true => Self::load_session_results(&self.session_results, self.facilitate_id, &self.facilitate_token, ctx.clone()),
+            false => Self::load_dashboard(&self.facilitator_view, self.facilitate_id, &self.facilitate_token, ctx.clone()),
+        }
+    }
+
+    fn run_admin_action(&mut self, action: AdminAction, ctx: &egui::Context) {
+        let Some((id, token)) = self.facilitator.clone() else { return };
+        match (action.path(), action) {
+            (Some(path), _) => {
+                let cloned_view = self.facilitator_view.clone();
+                let ctx = ctx.clone();
+                let request = authorised(Request::post(format!("http://127.0.0.1:8000/admin/{}/{}", id, path), vec![]), &Some(token.clone()));
+                fetch(request, move |_| Self::load_dashboard(&cloned_view, id, &token, ctx));
+            },
+            (None, AdminAction::Refresh) => Self::load_session_results(&self.session_results, id, &token, ctx.clone()),
+            (None, _) => {
+                self.facilitator = None;
+                *self.facilitator_view.lock().unwrap() = None;
+                *self.session_results.lock().unwrap() = None;
+            },
+        }
+    }
+
+    fn join_session(&mut self) {
+        let cloned_seat = self.pending_seat.clone();
+        let join = SessionJoin { name: self.player_name.clone() };
+        let url = format!("http://127.0.0.1:8000/session/{}/join", self.session_id);
+        fetch(Request::json(url, &join).unwrap(), move |response| {
+            if let Some(seat) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<SessionSeat>().ok()) {
+                *cloned_seat.lock().unwrap() = Some(seat);
+            }
+        });
+    }
+
+    // Creates a game from the form, then joins it in the chosen seat
+    fn create_game(&mut self) {
+        let cloned_seat = self.pending_seat.clone();
+        let cloned_created = self.created.clone();
+        let cloned_error = self.create_error.clone();
+        let pi = PlayerInfo { name: self.player_name.clone(), role: self.chosen_role };
+        *self.create_error.lock().unwrap() = None;
+        fetch(Request::json("http://127.0.0.1:8000/creategame", &self.new_game_settings).unwrap(), move |response| {
+            let Some(created) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<GameCreated>().ok()) else {
+                *cloned_error.lock().unwrap() = Some("The server would not create a game with these settings".to_owned());
+                return
+            };
+            let game_id = created.id;
+            *cloned_created.lock().unwrap() = Some(created);
+            fetch(Request::json(format!("http://127.0.0.1:8000/joingame/{}", game_id), &pi).unwrap(), move |response| {
+                match response.ok().filter(|r| r.ok).and_then(|r| r.json::<SeatGrant>().ok()) {
+                    Some(grant) => *cloned_seat.lock().unwrap() = Some(SessionSeat { game_id, role: pi.role, token: grant.token, game: grant.game }),
+                    None => *cloned_error.lock().unwrap() = Some(format!("Game {} was created but joining it as {:?} failed", game_id, pi.role)),
+                }
+            });
+        });
+    }
+
+    // Takes up a seat the server has given us, once it has told us which it is
+    fn take_pending_seat(&mut self) {
+        let Some(seat) = self.pending_seat.lock().unwrap().take() else { return };
+        self.player_info = Some(PlayerInfo { name: self.player_name.clone(), role: seat.role });
+        self.current_game_id = Some(seat.game_id);
+        *self.seat_token.lock().unwrap() = Some(seat.token);
+        *self.current_game.lock().unwrap() = Some(seat.game);
+    }
+
+    fn leave_game(&mut self) {
+        let token = self.seat_token.lock().unwrap().take();
+        if let Some(id) = self.current_game_id {
+            let url = format!("http://127.0.0.1:8000/leaveseat/{}", id);
+            fetch(authorised(Request::post(url, vec![]), &token), |_| {});
+        }
+        *self.current_game.lock().unwrap() = None;
+        self.current_game_id = None;
+        self.player_info = None;
+        self.offline = false;
+    }
+
+    fn start_offline_game(&mut self) {
+        let pi = PlayerInfo { name: self.player_name.clone(), role: self.chosen_role };
+        let game = offline::new_game(self.new_game_settings.clone(), &pi, &self.single_bots);
+        *self.current_game.lock().unwrap() = Some(game);
+        self.committed.lock().unwrap().clear();
+        self.current_game_id = None;
+        self.player_info = Some(pi);
+        self.offline = true;
     }
 
     fn update_games_list(&mut self) {
@@ -66,10 +332,79 @@ impl ClientApp {
             *cloned_games.lock().unwrap() = response.ok().unwrap().json::<Vec<GameListing>>().ok().unwrap();
         });
     }
+
+    // Sends an order, or an amendment or withdrawal of one, then fetches whatever the server now holds for that role.
+    // An offline game takes the order straight away instead
+    // These take the fields they need rather than self so they can be used alongside the UI's other borrows
+    fn submit_request(committed: &Arc<Mutex<HashMap<PlayerRole, PlayerRequest>>>, seat_token: &Mutex<Option<String>>, local: Option<&Mutex<Option<Game>>>, r: PlayerRequest) {
+        if let Some(local) = local {
+            match offline::submit(local.lock().unwrap().as_mut().unwrap(), &r) {
+                Ok(()) => {
+                    let mut map = committed.lock().unwrap();
+                    match r.withdraw {
+                        true => map.remove(&r.role),
+                        false => map.insert(r.role, r),
+                    };
+                },
+                Err(e) => log::warn!("Order not taken: {}", e),
+            }
+            return
+        }
+
+        let cloned_committed = committed.clone();
+        let token = seat_token.lock().unwrap().clone();
+        let committed_request = authorised(Request::get(format!("http://127.0.0.1:8000/committed/{}/{}", r.game_id, r.role as u32)), &token);
+        let role = r.role;
+        fetch(authorised(Request::json("http://127.0.0.1:8000/submitrequest", &r).unwrap(), &token), move |_| {
+            fetch(committed_request, move |response| {
+                let committed = response.ok().and_then(|r| r.json::<Option<PlayerRequest>>().ok()).flatten();
+                let mut map = cloned_committed.lock().unwrap();
+                match committed {
+                    Some(c) => map.insert(role, c),
+                    None => map.remove(&role),
+                };
+            });
+        });
+    }
+
+    fn committed_label(committed: &Mutex<HashMap<PlayerRole, PlayerRequest>>, ui: &mut egui::Ui, role: PlayerRole, week: u32) {
+        match committed.lock().unwrap().get(&role).filter(|c| c.week == week) {
+            Some(c) => ui.label(format!("Committed: {}", c.amount)),
+            None => ui.label("No order committed"),
+        };
+    }
+}
+
+const SESSION_KEY: &str = "session";
+
+// Session tokens are told apart from a game facilitator's by their holder
+fn is_session_token(token: &str) -> bool {
+    token.split('.').nth(1) == Some("session")
+}
+
+fn authorised(mut request: Request, token: &Option<String>) -> Request {
+    if let Some(token) = token {
+        request.headers.insert("Authorization", format!("Bearer {}", token));
+    }
+    request
 }
 
 impl eframe::App for ClientApp {
 
+    /// Remembers the seat we hold so a reload can rejoin the game
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let token = self.seat_token.lock().unwrap().clone();
+        let session = match (self.current_game_id, &self.player_info, token) {
+            (Some(game_id), Some(player_info), Some(token)) => serde_json::to_string(&(game_id, player_info, token)).unwrap(),
+            _ => String::new(),
+        };
+        storage.set_string(SESSION_KEY, session);
+    }
+
+    fn auto_save_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(1)
+    }
+
     /// Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
@@ -82,6 +417,9 @@ impl eframe::App for ClientApp {
             });
         });
 
+        self.take_pending_seat();
+        self.follow_game_events(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
             ui.heading("The Beer Distribution Game");
@@ -89,14 +427,91 @@ impl eframe::App for ClientApp {
 
             // Get a copy of the current game to do UI things
             let game = self.current_game.lock().unwrap().clone();
+            let mut leave = false;
+            let mut admin_action = None;
 
             match (game, &self.player_info) {
+                // Facilitator's dashboard
+                _ if self.facilitator.is_some() => {
+                    match (self.facilitator_view.lock().unwrap().as_ref(), self.session_results.lock().unwrap().as_ref()) {
+                        (Some(view), _) => admin_action = dashboard::show(ui, view),
+                        (_, Some(results)) => admin_action = dashboard::show_session(ui, results),
+                        (None, None) => {
+                            ui.label("Loading the dashboard...");
+                        },
+                    }
+                },
+
+                // Lobby, waiting for every seat to be taken and ready
+                (Some(game), Some(pi)) if game.status == GameStatus::Lobby => {
+                    match self.current_game_id {
+                        Some(id) => ui.heading(format!("{} (ID: {})", game.settings.name, id)),
+                        None => ui.heading(format!("{} (offline)", game.settings.name)),
+                    };
+                    ui.horizontal(|ui| {
+                        ui.heading(format!("{:?}", pi.role));
+                        if ui.button("Leave game").clicked() {
+                            leave = true;
+                        }
+                    });
+                    ui.label(&pi.name);
+                    ui.separator();
+
+                    let id = self.current_game_id.unwrap();
+                    ui.strong(format!("Join code: {}", id));
+                    ui.label("Others can enter this code under Join Multiplayer to take the remaining seats");
+                    if let Some(created) = self.created.lock().unwrap().as_ref().filter(|c| c.id == id) {
+                        ui.horizontal(|ui| {
+                            ui.label("You created this game. Keep the facilitator token to manage it from the dashboard");
+                            if ui.button("Copy facilitator token").clicked() {
+                                ui.output_mut(|o| o.copied_text = created.facilitator_token.clone());
+                            }
+                        });
+                    }
+                    ui.separator();
+
+                    ui.label("Waiting for the game to start");
+                    for role in &game.settings.chain {
+                        let status = match (game.settings.players.get(role).cloned().flatten(), game.is_ready(*role)) {
+                            (None, _) if game.settings.ordering_role(*role) != *role => format!("Ordered by {:?}", game.settings.ordering_role(*role)),
+                            (None, _) => "Waiting for a player".to_owned(),
+                            (Some(name), true) => format!("{} is ready", name),
+                            (Some(name), false) => name,
+                        };
+                        ui.label(format!("{:?}: {}", role, status));
+                    }
+                    let ready = game.ready.contains(&pi.role);
+                    if ui.add_enabled(game.seats_filled() && !ready, egui::Button::new("Ready")).clicked() {
+                        let cloned_game = self.current_game.clone();
+                        let token = self.seat_token.lock().unwrap().clone();
+                        let url = format!("http://127.0.0.1:8000/ready/{}", self.current_game_id.unwrap());
+                        fetch(authorised(Request::post(url, vec![]), &token), move |response| {
+                            if let Some(game) = response.ok().filter(|r| r.ok).and_then(|r| r.json::<Game>().ok()) {
+                                *cloned_game.lock().unwrap() = Some(game);
+                            }
+                        });
+                    }
+                },
+
                 // Game play UI
                 (Some(game), Some(pi)) => {
                     let state = game.states.last().unwrap();
+                    if let Some(deadline) = game.deadline {
+                        let left = (deadline as f64 - js_sys::Date::now() / 1000.0).max(0.0);
+                        ui.label(format!("Time left this turn: {}:{:02}", left as u64 / 60, left as u64 % 60));
+                        ctx.request_repaint_after(std::time::Duration::from_secs(1));
+                    }
 
-                    ui.heading(format!("{} (ID: {})", game.settings.name, self.current_game_id.unwrap()));
-                    ui.heading(format!("{:?}", pi.role));
+                    match self.current_game_id {
+                        Some(id) => ui.heading(format!("{} (ID: {})", game.settings.name, id)),
+                        None => ui.heading(format!("{} (offline)", game.settings.name)),
+                    };
+                    ui.horizontal(|ui| {
+                        ui.heading(format!("{:?}", pi.role));
+                        if ui.button("Leave game").clicked() {
+                            leave = true;
+                        }
+                    });
                     ui.label(&pi.name);
                     ui.separator();
                     ui.horizontal(|ui| {
@@ -109,26 +524,232 @@ impl eframe::App for ClientApp {
                             ui.heading("Current");
                             ui.label(format!("Stock: {}", state.players[pi.role].stock));
                             ui.label(format!("Deficit: {}", state.players[pi.role].deficit));
-                            ui.label(format!("Costs: {}", state.players[pi.role].costs));
+                            if state.players[pi.role].deficit > 0 {
+                                ui.collapsing("Open orders", |ui| {
+                                    for order in state.players[pi.role].orders.iter().filter(|o| o.amount > 0) {
+                                        ui.label(format!("Week {}: {} of {} filled, {} shipped this week", order.week, order.filled, order.amount, order.shipped));
+                                    }
+                                });
+                            }
+                            ui.label(format!("Costs: {}", state.role_costs(pi.role)));
+                            let ledger = state.players[pi.role].ledger;
+                            if ledger.ordering + ledger.purchasing > 0 {
+                                ui.label(format!("  Ordering: {}", ledger.ordering + ledger.purchasing));
+                            }
+                            if ledger.expediting > 0 {
+                                ui.label(format!("  Expediting: {}", ledger.expediting));
+                            }
+                            if let Some(shelf_life) = game.settings.shelf_life {
+                                ui.label(format!("Spoiled: {} (shelf life {} weeks)", state.players[pi.role].spoiled, shelf_life));
+                                ui.collapsing("Stock by age", |ui| {
+                                    for lot in &state.players[pi.role].lots {
+                                        ui.label(format!("{} weeks old: {}", lot.age, lot.amount));
+                                    }
+                                });
+                            }
+                            if ledger.carbon > 0 {
+                                ui.label(format!("  Carbon: {}", ledger.carbon));
+                            }
+                            ui.label(format!("Chain costs so far: {}", game.chain_costs()));
+                            let emissions = &state.players[pi.role].emissions;
+                            if emissions.total() > 0 || game.chain_emissions() > 0 {
+                                ui.collapsing(format!("Emissions: {} kg CO2", emissions.total()), |ui| {
+                                    ui.label(format!("Transport: {}", emissions.transport));
+                                    ui.label(format!("Storage: {}", emissions.storage));
+                                    ui.label(format!("Production: {}", emissions.production));
+                                    ui.label(format!("Expediting: {}", emissions.expediting));
+                                    ui.label(format!("Yours so far: {}", game.role_emissions(pi.role)));
+                                    ui.label(format!("Chain so far: {}", game.chain_emissions()));
+                                });
+                            }
+                            if game.settings.profit.is_some() {
+                                let p = &state.players[pi.role];
+                                ui.label(format!("Revenue: {}", p.revenue));
+                                ui.label(format!("Purchases: {}", p.purchases));
+                                ui.label(format!("Cash: {}", p.cash));
+                                if p.bankrupt {
+                                    ui.colored_label(egui::Color32::RED, "Bankrupt");
+                                }
+                            }
+                            ui.strong(format!("Score: {}", game.score(pi.role)));
+                            let summaries = game.contract_summaries();
+                            if state.game_end && !summaries.is_empty() {
+                                ui.collapsing("Contract debrief", |ui| {
+                                    egui::Grid::new("contracts").striped(true).show(ui, |ui| {
+                                        for heading in ["Buyer", "Supplier", "Wholesale paid", "At list price", "Revenue shared", "Buyback", "Buyer profit", "Supplier profit"] {
+                                            ui.strong(heading);
+                                        }
+                                        ui.end_row();
+                                        for s in &summaries {
+                                            ui.label(format!("{:?}", s.buyer));
+                                            ui.label(format!("{:?}", s.supplier));
+                                            ui.label(s.wholesale_paid.to_string());
+                                            ui.label(s.list_price_paid.to_string());
+                                            ui.label(s.revenue_shared.to_string());
+                                            ui.label(s.buyback_paid.to_string());
+                                            ui.label(s.buyer_profit.to_string());
+                                            ui.label(s.supplier_profit.to_string());
+                                            ui.end_row();
+                                        }
+                                    });
+                                    ui.strong(format!("Channel profit: {}", game.channel_profit()));
+                                });
+                            }
                         });
                         ui.vertical(|ui| {
                             ui.heading("Upstream");
-                            ui.horizontal(|ui| {
-                                ui.add(egui::widgets::DragValue::new(&mut self.outgoing_request));
-                                if ui.button("Submit").clicked() {
-                                    // Submit a request
-                                    let r = PlayerRequest {
-                                        game_id: self.current_game_id.unwrap(),
-                                        week: state.week,
-                                        role: pi.role,
-                                        amount: self.outgoing_request,
+                            let ordering_role = game.settings.ordering_role(pi.role);
+                            if game.settings.min_order > 0 {
+                                ui.label(format!("Minimum order: {}", game.settings.min_order));
+                            }
+                            if game.settings.order_multiple > 1 {
+                                ui.label(format!("Order in multiples of {}", game.settings.order_multiple));
+                            }
+                            if ordering_role == pi.role {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::widgets::DragValue::new(&mut self.outgoing_request));
+                                    if let Some(expedite) = &game.settings.expedite {
+                                        ui.label("Expedite:");
+                                        ui.add(egui::widgets::DragValue::new(&mut self.expedite_request))
+                                            .on_hover_text(format!("Arrives in {} weeks at {} extra per unit", expedite.lead_time, expedite.unit_cost));
+                                    }
+                                    if ui.button("Submit").clicked() {
+                                        let lines = self.alternative_requests.iter().enumerate()
+                                            .filter(|(_, amount)| **amount > 0)
+                                            .map(|(supplier, amount)| OrderLine { supplier, amount: *amount })
+                                            .collect();
+                                        // Submit a request
+                                        let r = PlayerRequest {
+                                            game_id: self.current_game_id.unwrap_or_default(),
+                                            week: state.week,
+                                            role: pi.role,
+                                            amount: self.outgoing_request,
+                                            submitted_by: None,
+                                            expedite: self.expedite_request,
+                                            lines,
+                                            product_amounts: self.product_requests.clone(),
+                                            withdraw: false,
+                                        };
+                                        Self::submit_request(&self.committed, &self.seat_token, self.offline.then_some(&*self.current_game), r);
                                     };
-                                    fetch(Request::json("http://127.0.0.1:8000/submitrequest", &r).unwrap(), |_| {});
-                                };
-                            });
+                                });
+                                ui.horizontal(|ui| {
+                                    Self::committed_label(&self.committed, ui, pi.role, state.week);
+                                    if ui.button("Withdraw").clicked() {
+                                        Self::submit_request(&self.committed, &self.seat_token, self.offline.then_some(&*self.current_game), PlayerRequest {
+                                            game_id: self.current_game_id.unwrap_or_default(),
+                                            week: state.week,
+                                            role: pi.role,
+                                            amount: 0,
+                                            submitted_by: None,
+                                            expedite: 0,
+                                            lines: vec![],
+                                            product_amounts: vec![0; game.settings.products.len()],
+                                            withdraw: true,
+                                        });
+                                    }
+                                });
+                                // Extra order lines for any alternative suppliers
+                                self.alternative_requests.resize(game.settings.alternative_suppliers.len(), 0);
+                                for (supplier, amount) in game.settings.alternative_suppliers.iter().zip(self.alternative_requests.iter_mut()) {
+                                    ui.horizontal(|ui| {
+                                        ui.add(egui::widgets::DragValue::new(amount));
+                                        ui.label(format!("from {} ({} weeks, {} per unit)", supplier.name, supplier.lead_time, supplier.unit_cost));
+                                    });
+                                }
+                            } else {
+                                ui.label(format!("Ordered by {:?}", ordering_role));
+                            }
                             ui.label(format!("Incoming: {}", state.players[pi.role].incoming));
+                            if game.settings.expedite.is_some() {
+                                ui.label(format!("Expedited in transit: {}", state.players[pi.role].expedited.in_transit()));
+                            }
+                            for (supplier, inbound) in game.settings.alternative_suppliers.iter().zip(&state.players[pi.role].alternative_inbound) {
+                                ui.label(format!("From {}: {}", supplier.name, inbound.in_transit()));
+                            }
+                            // The top of the chain runs the factory
+                            if game.settings.supplier_of(pi.role).is_none() {
+                                ui.label(format!("In production: {}", state.production.in_transit()));
+                                if state.production_scheduled > 0 {
+                                    ui.label(format!("Last scheduled: {} ({} lost)", state.production_scheduled, state.production_lost));
+                                }
+                            }
                         });
                     });
+
+                    // Further products, ordered alongside the main one
+                    if !game.settings.products.is_empty() {
+                        ui.separator();
+                        self.product_requests.resize(game.settings.products.len(), 0);
+                        egui::Grid::new("products").striped(true).show(ui, |ui| {
+                            for heading in ["Product", "Stock", "Deficit", "Requested", "Incoming", "Costs", "Order"] {
+                                ui.strong(heading);
+                            }
+                            ui.end_row();
+
+                            let first = &state.players[pi.role];
+                            for value in ["Beer".to_owned(), first.stock.to_string(), first.deficit.to_string(),
+                                          first.incoming_request.to_string(), first.incoming.to_string(), first.costs.to_string()] {
+                                ui.label(value);
+                            }
+                            ui.label(self.outgoing_request.to_string());
+                            ui.end_row();
+
+                            for ((product, product_state), amount) in game.settings.products.iter().zip(&state.products).zip(self.product_requests.iter_mut()) {
+                                let p = &product_state.players[pi.role];
+                                for value in [product.name.clone(), p.stock.to_string(), p.deficit.to_string(),
+                                              p.incoming_request.to_string(), p.incoming.to_string(), p.costs.to_string()] {
+                                    ui.label(value);
+                                }
+                                ui.add(egui::widgets::DragValue::new(amount));
+                                ui.end_row();
+                            }
+                        });
+                    }
+
+                    if !game.settings.alternative_suppliers.is_empty() {
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            ui.label("Sourced so far:");
+                            for (source, amount) in game.sourcing(pi.role) {
+                                ui.label(format!("{}: {}", source, amount));
+                            }
+                        });
+                    }
+
+                    // Vendor-managed customers, whose replenishment this seat decides
+                    for managed in game.settings.roles_ordered_by(pi.role).into_iter().filter(|r| *r != pi.role) {
+                        ui.separator();
+                        ui.heading(format!("Managing {:?}", managed));
+                        ui.horizontal(|ui| {
+                            ui.vertical(|ui| {
+                                ui.label(format!("Requested: {}", state.players[managed].incoming_request));
+                                ui.label(format!("Stock: {}", state.players[managed].stock));
+                                ui.label(format!("Deficit: {}", state.players[managed].deficit));
+                            });
+                            ui.vertical(|ui| {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::widgets::DragValue::new(&mut self.managed_request));
+                                    if ui.button("Submit").clicked() {
+                                        let r = PlayerRequest {
+                                            game_id: self.current_game_id.unwrap_or_default(),
+                                            week: state.week,
+                                            role: managed,
+                                            amount: self.managed_request,
+                                            submitted_by: Some(pi.role),
+                                            expedite: 0,
+                                            lines: vec![],
+                                            product_amounts: vec![0; game.settings.products.len()],
+                                            withdraw: false,
+                                        };
+                                        Self::submit_request(&self.committed, &self.seat_token, self.offline.then_some(&*self.current_game), r);
+                                    };
+                                });
+                                Self::committed_label(&self.committed, ui, managed, state.week);
+                                ui.label(format!("Incoming: {}", state.players[managed].incoming));
+                            });
+                        });
+                    }
                 }
 
                 // Game selection UI
@@ -144,25 +765,96 @@ impl eframe::App for ClientApp {
                         if ui.selectable_value(&mut self.game_style, GameStyleChoice::JoinMultiplayer, "Join Multiplayer").clicked() {
                             self.update_games_list()
                         };
+                        ui.selectable_value(&mut self.game_style, GameStyleChoice::Facilitate, "Facilitate");
                     });
 
                     match &self.game_style {
-                        GameStyleChoice::NewSingleplayer => {},
-                        GameStyleChoice::NewMultiplayer => {
+                        GameStyleChoice::NewSingleplayer => {
+                            setup::show(ui, &mut self.new_game_settings, &mut self.chosen_role);
+
+                            // Every other seat is a bot, each with its own way of ordering
+                            for role in self.new_game_settings.chain.iter().filter(|r| **r != self.chosen_role) {
+                                let bot = self.single_bots.get_mut(role).unwrap();
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{:?} bot:", role));
+                                    if ui.radio(*bot == BotPolicy::PassOn, "Pass on demand").clicked() {
+                                        *bot = BotPolicy::PassOn;
+                                    }
+                                    if ui.radio(matches!(bot, BotPolicy::BaseStock { .. }), "Base stock").clicked() && *bot == BotPolicy::PassOn {
+                                        *bot = BotPolicy::BaseStock { target: 12 };
+                                    }
+                                    if let BotPolicy::BaseStock { target } = bot {
+                                        ui.add(egui::widgets::DragValue::new(target).prefix("target "));
+                                    }
+                                });
+                            }
+
                             if ui.button("Start game").clicked() {
-                                log::info!("player_name = {:?}", self.player_name);
+                                self.start_offline_game();
+                            }
+                        },
+                        GameStyleChoice::NewMultiplayer => {
+                            setup::show(ui, &mut self.new_game_settings, &mut self.chosen_role);
+                            if ui.button("Create game").clicked() {
+                                self.create_game();
+                            }
+                            if let Some(error) = self.create_error.lock().unwrap().as_ref() {
+                                ui.colored_label(egui::Color32::RED, error);
+                            }
+                        },
+                        GameStyleChoice::Facilitate => {
+                            ui.horizontal(|ui| {
+                                ui.label("Game or session id:");
+                                ui.add(egui::widgets::DragValue::new(&mut self.facilitate_id));
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("Facilitator token:");
+                                ui.text_edit_singleline(&mut self.facilitate_token);
+                            });
+                            if ui.button("Open dashboard").clicked() {
+                                self.open_dashboard(ui.ctx());
                             }
                         },
                         GameStyleChoice::JoinMultiplayer => {
-                            // Fetch available games
-                            if ui.button("Refresh").clicked() {self.update_games_list()};
+                            // Join a classroom session and let the server pick the seat
+                            ui.horizontal(|ui| {
+                                ui.label("Session:");
+                                ui.add(egui::widgets::DragValue::new(&mut self.session_id));
+                                if ui.button("Join session").clicked() {
+                                    self.join_session();
+                                }
+                            });
 
                             ui.separator();
 
-                            // List available games
-                            for game in self.available_games.lock().unwrap().to_owned() {
+                            // Fetch available games, or just the one whose join code was given
+                            ui.horizontal(|ui| {
+                                ui.label("Join code:");
+                                ui.text_edit_singleline(&mut self.join_code);
+                                if ui.button("Refresh").clicked() {self.update_games_list()};
+                            });
+
+                            ui.separator();
+
+                            // List available games, those still in the lobby first
+                            let mut games = self.available_games.lock().unwrap().to_owned();
+                            games.retain(|g| matches!(g.status, GameStatus::Lobby | GameStatus::Running | GameStatus::Paused));
+                            if let Ok(code) = self.join_code.trim().parse::<i64>() {
+                                games.retain(|g| g.id == code);
+                            }
+                            games.sort_by_key(|g| g.status != GameStatus::Lobby);
+                            let mut heading = None;
+                            for game in games {
+                                let group = match game.status {
+                                    GameStatus::Lobby => "Waiting to start",
+                                    _ => "In progress",
+                                };
+                                if heading != Some(group) {
+                                    ui.strong(group);
+                                    heading = Some(group);
+                                }
                                 ui.horizontal(|ui| {
-                                    ui.label(game.name);
+                                    ui.label(format!("{} (code {})", game.name, game.id));
                                     for role in game.available_roles {
                                         if ui.button(format!("Join as {:?}", role)).clicked() { 
                                             let pi = PlayerInfo {
@@ -170,11 +862,16 @@ impl eframe::App for ClientApp {
                                                                                 role: role,
                                                                             };
                                             let cloned_game = self.current_game.clone();
+                                            let cloned_token = self.seat_token.clone();
                                             let url = format!("http://127.0.0.1:8000/joingame/{}", game.id);
                                             fetch(Request::json(url, &pi).unwrap(), move |response| {
                                                 let r = response.unwrap();
                                                 match r.ok {
-                                                    true => *cloned_game.lock().unwrap() = Some(r.json::<Game>().ok().unwrap()),
+                                                    true => {
+                                                        let grant = r.json::<SeatGrant>().ok().unwrap();
+                                                        *cloned_token.lock().unwrap() = Some(grant.token);
+                                                        *cloned_game.lock().unwrap() = Some(grant.game);
+                                                    },
                                                     false => {}
                                                 };
                                             });
@@ -193,6 +890,13 @@ impl eframe::App for ClientApp {
 
             
 
+            if leave {
+                self.leave_game();
+            }
+            if let Some(action) = admin_action {
+                self.run_admin_action(action, ui.ctx());
+            }
+
             ui.separator();
 
             ui.code(format!("{:#?}", self.current_game.lock().unwrap()));
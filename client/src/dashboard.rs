@@ -0,0 +1,160 @@
+use game::{FacilitatorView, GameStatus, TeamResult};
+
+// Buttons on the facilitator's dashboard, carried out by the app since they need to talk to the server
+pub enum AdminAction {
+    Start,
+    Pause,
+    Resume,
+    Advance,
+    End,
+    Archive,
+    Refresh,
+    Close,
+}
+
+impl AdminAction {
+    // Where on the server each action is sent, for those that are
+    pub fn path(&self) -> Option<&'static str> {
+        match self {
+            AdminAction::Start => Some("start"),
+            AdminAction::Pause => Some("pause"),
+            AdminAction::Resume => Some("resume"),
+            AdminAction::Advance => Some("advance"),
+            AdminAction::End => Some("end"),
+            AdminAction::Archive => Some("archive"),
+            AdminAction::Refresh | AdminAction::Close => None,
+        }
+    }
+}
+
+// Every tier of the game side by side, with the controls the facilitator has over it
+pub fn show(ui: &mut egui::Ui, view: &FacilitatorView) -> Option<AdminAction> {
+    let game = &view.game;
+    let state = game.states.last().unwrap();
+    let mut action = None;
+
+    ui.horizontal(|ui| {
+        ui.heading(format!("Facilitating {}", game.settings.name));
+        if ui.button("Close").clicked() {
+            action = Some(AdminAction::Close);
+        }
+    });
+    ui.label(format!("Week {} of {}, {:?}", state.week, game.settings.max_weeks, game.status));
+
+    ui.horizontal(|ui| {
+        match game.status {
+            GameStatus::Lobby => {
+                if ui.add_enabled(game.seats_filled(), egui::Button::new("Start")).clicked() {
+                    action = Some(AdminAction::Start);
+                }
+            },
+            GameStatus::Running => {
+                if ui.button("Pause").clicked() {
+                    action = Some(AdminAction::Pause);
+                }
+            },
+            GameStatus::Paused => {
+                if ui.button("Resume").clicked() {
+                    action = Some(AdminAction::Resume);
+                }
+            },
+            GameStatus::Finished => {
+                if ui.button("Archive").clicked() {
+                    action = Some(AdminAction::Archive);
+                }
+            },
+            GameStatus::Archived => (),
+        }
+        if game.status == GameStatus::Running && ui.button("Advance with default orders").clicked() {
+            action = Some(AdminAction::Advance);
+        }
+        if matches!(game.status, GameStatus::Lobby | GameStatus::Running | GameStatus::Paused) && ui.button("End game").clicked() {
+            action = Some(AdminAction::End);
+        }
+    });
+    ui.separator();
+
+    egui::Grid::new("dashboard").striped(true).show(ui, |ui| {
+        ui.label("");
+        for role in &game.settings.chain {
+            ui.strong(format!("{:?}", role));
+        }
+        ui.end_row();
+
+        let row = |ui: &mut egui::Ui, heading: &str, value: &dyn Fn(game::PlayerRole) -> String| {
+            ui.label(heading);
+            for role in &game.settings.chain {
+                ui.label(value(*role));
+            }
+            ui.end_row();
+        };
+        row(ui, "Player", &|r| game.settings.players.get(&r).cloned().flatten().unwrap_or("Empty".to_owned()));
+        if game.status == GameStatus::Lobby {
+            row(ui, "Ready", &|r| if game.is_ready(r) { "Yes".to_owned() } else { "No".to_owned() });
+        }
+        row(ui, "Ordered this week", &|r| if view.submitted.contains(&r) { "Yes".to_owned() } else { "Waiting".to_owned() });
+        row(ui, "Stock", &|r| state.role_stock(r).to_string());
+        row(ui, "Deficit", &|r| state.players[r].deficit.to_string());
+        row(ui, "Incoming", &|r| state.players[r].incoming.to_string());
+        row(ui, "Outgoing", &|r| state.players[r].outgoing.to_string());
+        row(ui, "Costs this week", &|r| state.role_costs(r).to_string());
+        row(ui, "Costs so far", &|r| game.states.iter().map(|s| s.role_costs(r)).sum::<u32>().to_string());
+        if game.settings.profit.is_some() {
+            row(ui, "Profit so far", &|r| game.score(r).to_string());
+        }
+    });
+    ui.strong(format!("Chain costs so far: {}", game.chain_costs()));
+
+    ui.collapsing("History", |ui| {
+        for (_, week, entry) in &view.history {
+            ui.label(format!("Week {}: {}", week, entry));
+        }
+    });
+
+    action
+}
+
+// Every team of a classroom session side by side
+pub fn show_session(ui: &mut egui::Ui, results: &[TeamResult]) -> Option<AdminAction> {
+    let mut action = None;
+
+    ui.horizontal(|ui| {
+        ui.heading("Session results");
+        if ui.button("Refresh").clicked() {
+            action = Some(AdminAction::Refresh);
+        }
+        if ui.button("Close").clicked() {
+            action = Some(AdminAction::Close);
+        }
+    });
+    ui.separator();
+
+    egui::Grid::new("session").striped(true).show(ui, |ui| {
+        ui.strong("Team");
+        ui.strong("Game");
+        ui.strong("Week");
+        ui.strong("Chain costs");
+        for (role, _) in results.first().map(|r| r.role_costs.as_slice()).unwrap_or_default() {
+            ui.strong(format!("{:?} costs", role));
+            ui.strong(format!("{:?} bullwhip", role));
+        }
+        ui.end_row();
+
+        for result in results {
+            ui.label(result.team.to_string());
+            ui.label(result.game_id.to_string());
+            ui.label(match result.finished {
+                true => "Finished".to_owned(),
+                false => result.week.to_string(),
+            });
+            ui.label(result.chain_costs.to_string());
+            for ((_, costs), (_, bullwhip)) in result.role_costs.iter().zip(&result.bullwhip) {
+                ui.label(costs.to_string());
+                ui.label(bullwhip.map_or("-".to_owned(), |b| format!("{:.2}", b)));
+            }
+            ui.end_row();
+        }
+    });
+
+    action
+}
@@ -1,4 +1,7 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod dashboard;
+mod offline;
+mod setup;
 pub use app::ClientApp;
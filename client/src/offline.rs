@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+use game::{BotPolicy, Game, GameSettings, PlayerInfo, PlayerRequest, PlayerRole, RequestError};
+
+// A game run entirely in the client, with bots in every seat but the player's
+pub fn new_game(mut settings: GameSettings, player: &PlayerInfo, bots: &HashMap<PlayerRole, BotPolicy>) -> Game {
+    settings.bots = bots.iter()
+        .filter(|(role, _)| **role != player.role && settings.chain.contains(role))
+        .map(|(role, bot)| (*role, *bot))
+        .collect();
+    settings.players.insert(player.role, Some(player.name.clone()));
+    let mut game = Game::new(settings);
+    game.start();
+    game
+}
+
+// Takes an order the way the server would: the bots order once the player has, then the week moves on
+pub fn submit(game: &mut Game, request: &PlayerRequest) -> Result<(), RequestError> {
+    game.validate_submission(request)?;
+    let settings = game.settings.clone();
+    let state = game.states.last_mut().unwrap();
+    state.receive_request(request, &settings)?;
+
+    let player_done = settings.chain.iter()
+        .all(|r| settings.bot_for(*r).is_some() || state.players[*r].outgoing_request.is_some());
+    if player_done {
+        for role in settings.chain.iter().copied() {
+            if let Some(bot) = state.bot_request(0, role, &settings) {
+                state.receive_request(&bot, &settings)?;
+            }
+        }
+    }
+
+    if state.get_ready_state(&settings) {
+        game.take_turn();
+    }
+    Ok(())
+}
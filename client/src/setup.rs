@@ -0,0 +1,64 @@
+use game::{Demand, GameSettings, PlayerRole};
+
+// The settings a new game starts with, and the seat the player takes in it
+pub fn show(ui: &mut egui::Ui, settings: &mut GameSettings, role: &mut PlayerRole) {
+    egui::Grid::new("setup").num_columns(2).show(ui, |ui| {
+        ui.label("Game name:");
+        ui.text_edit_singleline(&mut settings.name);
+        ui.end_row();
+
+        ui.label("Weeks:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.max_weeks).range(2..=100));
+        ui.end_row();
+
+        ui.label("Starting orders:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.initial_request));
+        ui.end_row();
+
+        ui.label("Stock cost:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.stock_cost));
+        ui.end_row();
+
+        ui.label("Backlog cost:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.deficit_cost));
+        ui.end_row();
+
+        ui.label("Cost per order:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.order_cost));
+        ui.end_row();
+
+        ui.label("Production delay:");
+        ui.add(egui::widgets::DragValue::new(&mut settings.production.lead_time).range(1..=10).suffix(" weeks"));
+        ui.end_row();
+
+        ui.label("Customer demand:");
+        ui.horizontal(|ui| {
+            let current = settings.demand.at(1);
+            if ui.radio(matches!(settings.demand, Demand::Constant(_)), "Constant").clicked() {
+                settings.demand = Demand::Constant(current);
+            }
+            if ui.radio(matches!(settings.demand, Demand::Step { .. }), "Step").clicked() && matches!(settings.demand, Demand::Constant(_)) {
+                settings.demand = Demand::Step { initial: current, stepped: current * 2, week: 5 };
+            }
+            match &mut settings.demand {
+                Demand::Constant(amount) => {
+                    ui.add(egui::widgets::DragValue::new(amount));
+                },
+                Demand::Step { initial, stepped, week } => {
+                    ui.add(egui::widgets::DragValue::new(initial).prefix("from "));
+                    ui.add(egui::widgets::DragValue::new(stepped).prefix("to "));
+                    ui.add(egui::widgets::DragValue::new(week).prefix("in week "));
+                },
+            }
+        });
+        ui.end_row();
+
+        ui.label("Play as:");
+        ui.horizontal(|ui| {
+            for chain_role in &settings.chain {
+                ui.selectable_value(role, *chain_role, format!("{:?}", chain_role));
+            }
+        });
+        ui.end_row();
+    });
+}
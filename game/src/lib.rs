@@ -1,5 +1,7 @@
-use std::{collections::HashMap, ops::{Index, IndexMut}};
+use std::{collections::HashMap, fmt, ops::{Index, IndexMut}};
 use serde::{Serialize, Deserialize};
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Binomial, Distribution};
 
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone, Copy, Eq, Hash)]
 pub enum PlayerRole {
@@ -10,7 +12,7 @@ pub enum PlayerRole {
 }
 
 impl PlayerRole {
-    const ROLES: [Self; 4] = [  PlayerRole::Retailer, 
+    pub const ROLES: [Self; 4] = [  PlayerRole::Retailer, 
                                 PlayerRole::Wholesaler, 
                                 PlayerRole::Distributor, 
                                 PlayerRole::Manufacturer];
@@ -30,33 +32,491 @@ pub struct GameSettings {
     pub stock_cost: u32,
     pub deficit_cost: u32,
     pub players: HashMap<PlayerRole, Option<String>>,
+    // Vendor-managed inventory: roles listed here have their orders placed by their supplier's seat
+    #[serde(default)]
+    pub vendor_managed: Vec<PlayerRole>,
+    // Tiers taking part in the game, from the customer end upwards. Leaving a tier out bypasses it
+    #[serde(default = "default_chain")]
+    pub chain: Vec<PlayerRole>,
+    // Fixed cost charged for every order placed, regardless of its size
+    #[serde(default)]
+    pub order_cost: u32,
+    // Purchase cost per unit ordered
+    #[serde(default)]
+    pub unit_cost: u32,
+    // Smallest order that can be placed, an order of zero is always allowed
+    #[serde(default)]
+    pub min_order: u32,
+    // Orders must be whole multiples of this, e.g. a case pack. Zero or one allows any amount
+    #[serde(default)]
+    pub order_multiple: u32,
+    // Emergency shipping at a premium, disabled when not set
+    #[serde(default)]
+    pub expedite: Option<ExpediteSettings>,
+    // Outside sources any tier can buy from alongside its regular supplier
+    #[serde(default)]
+    pub alternative_suppliers: Vec<AlternativeSupplier>,
+    // How the factory feeding the top of the chain behaves
+    #[serde(default)]
+    pub production: ProductionSettings,
+    // Weeks stock keeps once warehoused before it spoils, never spoils when not set
+    #[serde(default)]
+    pub shelf_life: Option<u32>,
+    // Cost of writing off each spoiled unit
+    #[serde(default)]
+    pub spoilage_cost: u32,
+    // What end customers buy each week
+    #[serde(default)]
+    pub demand: Demand,
+    // Further products handled by the same chain. The settings above describe the first product
+    #[serde(default)]
+    pub products: Vec<Product>,
+    // Warehouse space per tier shared by all products, zero for no limit
+    #[serde(default)]
+    pub storage_capacity: u32,
+    // Cost per unit stored beyond the shared capacity
+    #[serde(default)]
+    pub overflow_cost: u32,
+    // Play for profit rather than lowest costs, disabled when not set
+    #[serde(default)]
+    pub profit: Option<ProfitSettings>,
+    // Terms agreed between a buyer, the key here, and its supplier. Payments need profit mode
+    #[serde(default)]
+    pub contracts: HashMap<PlayerRole, Contract>,
+    #[serde(default)]
+    pub emissions: EmissionSettings,
+    // How a tier short of stock splits it over its customer's open orders. Each tier has a single customer and
+    // always ships as much as it can, so the rule only decides which orders are marked as filled, not what is shipped
+    // or what anything costs
+    #[serde(default)]
+    pub allocation: Allocation,
+    // Time allowed for each turn, after which missing orders are filled in for the seats
+    #[serde(default)]
+    pub turn_deadline: Option<TurnDeadline>,
+    // Seats played by the server, each ordering by its own policy
+    #[serde(default)]
+    pub bots: HashMap<PlayerRole, BotPolicy>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProfitSettings {
+    // Price each tier sells at, the Retailer's being what end customers pay
+    pub prices: HashMap<PlayerRole, u32>,
+    // What the factory charges the top of the chain per unit
+    pub production_price: u32,
+    pub starting_cash: i64,
+    // How far cash may go below zero before a tier is bankrupt
+    #[serde(default)]
+    pub credit_limit: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Demand {
+    Constant(u32),
+    // The classic beer game: demand jumps from one level to another at the given week
+    Step { initial: u32, stepped: u32, week: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TurnDeadline {
+    pub seconds: u64,
+    pub default_order: DefaultOrder,
+}
+
+// What is ordered for a seat that misses the deadline
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DefaultOrder {
+    RepeatLast,
+    // Order whatever the customer asked for this week
+    MatchDemand,
+    Bot(BotPolicy),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BotPolicy {
+    // Replace this week's demand and top stock and what is on its way back up to the target
+    BaseStock { target: u32 },
+    // Order whatever the customer asked for this week
+    PassOn,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Allocation {
+    // Oldest order first
+    #[default]
+    Fifo,
+    // Every open order gets the same fraction of what it is still owed
+    Proportional,
+    // This week's order is served before any backorder
+    Priority,
+    // Stock is split evenly over the open orders, passing on whatever an order does not need
+    FairShare,
+}
+
+// An order from a tier's customer and how much of it has been shipped so far
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CustomerOrder {
+    pub week: u32,
+    pub amount: u32,
+    pub filled: u32,
+    // Shipped against this order this week
+    pub shipped: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Product {
+    pub name: String,
+    pub initial_request: u32,
+    pub stock_cost: u32,
+    pub deficit_cost: u32,
+    pub demand: Demand,
+    #[serde(default)]
+    pub shelf_life: Option<u32>,
+    #[serde(default)]
+    pub spoilage_cost: u32,
+}
+
+// Emissions per unit, in kg of CO2
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EmissionSettings {
+    // Per unit shipped by a tier to its customer
+    pub transport: HashMap<PlayerRole, u32>,
+    // Per unit held in stock for a week
+    pub storage: u32,
+    // Per unit put into production, expedited or not, charged to the top of the chain
+    pub production: u32,
+    // Per unit expedited, on top of the link's transport
+    pub expediting: u32,
+    // Cost per kg, which adds emissions to the costs when set
+    pub carbon_price: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Contract {
+    // Replaces the supplier's usual price on this link
+    pub wholesale_price: Option<u32>,
+    // Paid by the supplier for each unit the buyer still holds when the game ends
+    pub buyback_price: u32,
+    // Percentage of the buyer's revenue passed on to the supplier every week
+    pub revenue_share: u32,
+    // Percentage an order may move away from the previous week's order, no limit when not set
+    pub quantity_flexibility: Option<u32>,
+}
+
+// How a contract played out over a game
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContractSummary {
+    pub buyer: PlayerRole,
+    pub supplier: PlayerRole,
+    // Paid for deliveries under the contract, and what they would have cost at the supplier's usual price
+    pub wholesale_paid: i64,
+    pub list_price_paid: i64,
+    pub revenue_shared: i64,
+    pub buyback_paid: i64,
+    pub buyer_profit: i64,
+    pub supplier_profit: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExpediteSettings {
+    // Weeks until expedited units are in stock, regular deliveries take two
+    pub lead_time: u32,
+    // Premium paid per expedited unit on top of the usual unit cost
+    pub unit_cost: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProductionSettings {
+    // Weeks between scheduling production and shipping it
+    pub lead_time: u32,
+    // Chance in percent that each scheduled unit is produced successfully
+    pub yield_percent: u32,
+    // Seeds the yield losses so a game can be replayed
+    pub seed: u64,
+    // Production runs in whole batches of this size, zero for any amount
+    pub batch_size: u32,
+    // Most that can be scheduled in a week, zero for no limit
+    pub capacity: u32,
+}
+
+// Another supplier or spot market, never short of stock
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlternativeSupplier {
+    pub name: String,
+    // Weeks until units bought here are in stock
+    pub lead_time: u32,
+    // Replaces the game's usual unit cost for units bought here
+    pub unit_cost: u32,
+}
+
+fn default_chain() -> Vec<PlayerRole> {
+    PlayerRole::ROLES.to_vec()
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PlayerRequest {
     pub game_id: i64,
     pub week: u32,
     pub role: PlayerRole,
     pub amount: u32,
+    // The seat that placed the order, only needed when ordering on behalf of another role
+    #[serde(default)]
+    pub submitted_by: Option<PlayerRole>,
+    // Units to be rushed through the expedited channel, on top of the regular amount
+    #[serde(default)]
+    pub expedite: u32,
+    // Order lines placed with alternative suppliers, on top of the regular amount
+    #[serde(default)]
+    pub lines: Vec<OrderLine>,
+    // Amounts ordered of each further product, in the order the settings list them
+    #[serde(default)]
+    pub product_amounts: Vec<u32>,
+    // Takes back the order placed earlier in the week instead of placing a new one
+    #[serde(default)]
+    pub withdraw: bool,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct OrderLine {
+    // Index into the game's alternative suppliers
+    pub supplier: usize,
+    pub amount: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RequestError {
+    NotAuthorised { submitter: PlayerRole, role: PlayerRole },
+    NotInChain(PlayerRole),
+    BelowMinimumOrder { amount: u32, minimum: u32 },
+    NotOrderMultiple { amount: u32, multiple: u32 },
+    ExpediteUnavailable,
+    UnknownSupplier(usize),
+    WrongProductCount { expected: usize, received: usize },
+    OutsideFlexibility { amount: u32, minimum: u32, maximum: u32 },
+    WeekClosed { week: u32, current: u32 },
+    GameOver,
+    SeatEmpty(PlayerRole),
+    Paused,
+    NotStarted,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct PlayerState {
+    // Always the total of the lots below
     pub stock: u32,
     pub deficit: u32,
     pub incoming: u32,
     pub outgoing: u32,
     pub incoming_request: u32,
     pub outgoing_request: Option<u32>,
+    #[serde(default)]
+    pub ordered_by: Option<PlayerRole>,
     pub costs: u32,
+    // Where this week's costs came from
+    #[serde(default)]
+    pub ledger: CostLedger,
+    // Expedited units ordered this week, and how many of them the supplier managed to ship
+    #[serde(default)]
+    pub expedite_request: u32,
+    #[serde(default)]
+    pub expedite_filled: u32,
+    #[serde(default)]
+    pub expedited: Pipeline,
+    // Amounts ordered from each alternative supplier this week, and what is on its way from them
+    #[serde(default)]
+    pub alternative_orders: Vec<u32>,
+    #[serde(default)]
+    pub alternative_inbound: Vec<Pipeline>,
+    // Stock on hand by age, oldest first
+    #[serde(default)]
+    pub lots: Vec<Lot>,
+    // Units written off this week
+    #[serde(default)]
+    pub spoiled: u32,
+    // Profit mode: money in from shipments and out for deliveries this week, and the running balance
+    #[serde(default)]
+    pub revenue: u32,
+    #[serde(default)]
+    pub purchases: u32,
+    #[serde(default)]
+    pub cash: i64,
+    #[serde(default)]
+    pub bankrupt: bool,
+    // Units delivered by the regular supplier this week
+    #[serde(default)]
+    pub received: u32,
+    // Last week's order, which contracts with quantity flexibility hold this week's order to
+    #[serde(default)]
+    pub last_order: Option<u32>,
+    // Contract settlements this week: revenue passed to the supplier, buyback received at the end of
+    // the game, and the net amount this tier received under all of its contracts
+    #[serde(default)]
+    pub revenue_shared: u32,
+    #[serde(default)]
+    pub buyback: u32,
+    #[serde(default)]
+    pub contract_payments: i64,
+    // Emissions caused by this tier this week
+    #[serde(default)]
+    pub emissions: EmissionLedger,
+    // The customer's orders that were open this week, oldest first
+    #[serde(default)]
+    pub orders: Vec<CustomerOrder>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Lot {
+    // Weeks since the lot was warehoused
+    pub age: u32,
+    pub amount: u32,
+}
+
+// Units in transit, the front of the queue arrives next week
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct Pipeline(Vec<u32>);
+
+// Production used to be saved as the single amount arriving next week
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedPipeline {
+    Weeks(Vec<u32>),
+    NextWeek(u32),
+}
+
+impl<'de> Deserialize<'de> for Pipeline {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match SavedPipeline::deserialize(deserializer)? {
+            SavedPipeline::Weeks(weeks) => Pipeline(weeks),
+            SavedPipeline::NextWeek(amount) => Pipeline(vec![amount]),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct CostLedger {
+    pub holding: u32,
+    pub backlog: u32,
+    pub ordering: u32,
+    pub purchasing: u32,
+    #[serde(default)]
+    pub expediting: u32,
+    #[serde(default)]
+    pub spoilage: u32,
+    #[serde(default)]
+    pub overflow: u32,
+    #[serde(default)]
+    pub carbon: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct EmissionLedger {
+    pub transport: u32,
+    pub storage: u32,
+    pub production: u32,
+    pub expediting: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GameState {
     pub week: u32,
     pub game_end: bool,
     pub players: [PlayerState; 4],
-    pub production: u32
+    pub production: Pipeline,
+    // Production scheduled this week after batching, and how much of it was lost to yield
+    #[serde(default)]
+    pub production_scheduled: u32,
+    #[serde(default)]
+    pub production_lost: u32,
+    // One state per further product, each run through the engine with that product's settings
+    #[serde(default)]
+    pub products: Vec<GameState>,
+}
+
+// Something that happened in a game, pushed to everyone watching it. Order amounts stay private
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum GameEvent {
+    PlayerJoined { role: PlayerRole, name: String },
+    PlayerLeft { role: PlayerRole },
+    OrderSubmitted { role: PlayerRole },
+    OrderWithdrawn { role: PlayerRole },
+    WeekAdvanced { week: u32 },
+    GameEnded,
+    Paused,
+    Resumed,
+    Ready { role: PlayerRole },
+    Started,
+}
+
+// What the facilitator's dashboard shows: the whole game, who has ordered this week and what has been
+// done to the game, as (time, week, action)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FacilitatorView {
+    pub game: Game,
+    pub submitted: Vec<PlayerRole>,
+    pub history: Vec<(i64, u32, String)>,
+}
+
+// Sent back on creating a game. The token is the facilitator's, for managing the game's seats
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GameCreated {
+    pub id: i64,
+    pub facilitator_token: String,
+}
+
+// Sent back on joining a game. The token has to accompany everything the player does from that seat
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SeatGrant {
+    pub token: String,
+    pub game: Game,
+}
+
+// A class running the same game in parallel teams, one game per team
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionSettings {
+    pub teams: u32,
+    pub settings: GameSettings,
+}
+
+// Sent back on creating a session. The token is the facilitator's, for the whole session
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionCreated {
+    pub id: i64,
+    // Each team's game, with a facilitator token of its own for the dashboard
+    pub games: Vec<GameCreated>,
+    pub facilitator_token: String,
+}
+
+// A student arriving at a session, who is seated wherever there is room
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionJoin {
+    pub name: String,
+}
+
+// Sent back on joining a session, with the seat the student was put in
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionSeat {
+    pub game_id: i64,
+    pub role: PlayerRole,
+    pub token: String,
+    pub game: Game,
+}
+
+// How one team of a session is doing, for comparing teams side by side
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TeamResult {
+    pub team: u32,
+    pub game_id: i64,
+    pub week: u32,
+    pub finished: bool,
+    pub players: Vec<(PlayerRole, Option<String>)>,
+    pub role_costs: Vec<(PlayerRole, u32)>,
+    pub chain_costs: u32,
+    pub bullwhip: Vec<(PlayerRole, Option<f64>)>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -64,12 +524,61 @@ pub struct GameListing {
     pub id: i64,
     pub name: String,
     pub available_roles: Vec<PlayerRole>,
+    pub status: GameStatus,
+}
+
+// Where a game is in its life. Games wait in the lobby until every seat is taken and the players are ready
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum GameStatus {
+    Lobby,
+    Running,
+    Paused,
+    Finished,
+    Archived,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "SavedGame")]
 pub struct Game {
     pub settings: GameSettings,
     pub states: Vec<GameState>,
+    // When the current turn closes, in seconds since the Unix epoch. Kept by the server
+    pub deadline: Option<u64>,
+    pub status: GameStatus,
+    // Seats whose players are ready to start, while the game is in the lobby
+    pub ready: Vec<PlayerRole>,
+}
+
+// A game as it may have been saved by an older version, before it had a status
+#[derive(Deserialize)]
+struct SavedGame {
+    settings: GameSettings,
+    states: Vec<GameState>,
+    #[serde(default)]
+    deadline: Option<u64>,
+    status: Option<GameStatus>,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    ready: Vec<PlayerRole>,
+}
+
+impl From<SavedGame> for Game {
+    fn from(saved: SavedGame) -> Self {
+        // Games saved before there was a lobby were already under way, unless paused or over
+        let status = saved.status.unwrap_or(match saved.states.last() {
+            Some(state) if state.game_end => GameStatus::Finished,
+            _ if saved.paused => GameStatus::Paused,
+            _ => GameStatus::Running,
+        });
+        Game {
+            settings: saved.settings,
+            states: saved.states,
+            deadline: saved.deadline,
+            status,
+            ready: saved.ready,
+        }
+    }
 }
 
 impl TryFrom<u32> for PlayerRole {
@@ -86,6 +595,26 @@ impl TryFrom<u32> for PlayerRole {
     }
 }
 
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::NotAuthorised { submitter, role } => write!(f, "{:?} is not allowed to order for {:?}", submitter, role),
+            RequestError::NotInChain(role) => write!(f, "{:?} has been removed from this supply chain", role),
+            RequestError::BelowMinimumOrder { amount, minimum } => write!(f, "Order of {} is below the minimum order of {}", amount, minimum),
+            RequestError::NotOrderMultiple { amount, multiple } => write!(f, "Order of {} is not a multiple of {}", amount, multiple),
+            RequestError::ExpediteUnavailable => write!(f, "Expedited shipping is not available in this game"),
+            RequestError::UnknownSupplier(supplier) => write!(f, "There is no alternative supplier {}", supplier),
+            RequestError::WrongProductCount { expected, received } => write!(f, "Expected orders for {} further products but received {}", expected, received),
+            RequestError::OutsideFlexibility { amount, minimum, maximum } => write!(f, "Order of {} is outside the contracted range of {} to {}", amount, minimum, maximum),
+            RequestError::WeekClosed { week, current } => write!(f, "Orders are only taken for week {}, not week {}", current, week),
+            RequestError::GameOver => write!(f, "The game has already ended"),
+            RequestError::SeatEmpty(role) => write!(f, "Nobody has joined the game as {:?}", role),
+            RequestError::Paused => write!(f, "The game is paused"),
+            RequestError::NotStarted => write!(f, "The game has not started yet"),
+        }
+    }
+}
+
 // Lots of boilerplate to make this array indexable by an enum. Might just be an outdated habit of mine, but surely there is a nicer way to do this
 impl Index<PlayerRole> for [PlayerState] {
     type Output = PlayerState;
@@ -111,92 +640,706 @@ impl IndexMut<PlayerRole> for [PlayerState] {
     }
 }
 
+impl CostLedger {
+    pub fn total(&self) -> u32 {
+        self.holding + self.backlog + self.ordering + self.purchasing + self.expediting + self.spoilage + self.overflow + self.carbon
+    }
+}
+
+impl EmissionLedger {
+    pub fn total(&self) -> u32 {
+        self.transport + self.storage + self.production + self.expediting
+    }
+}
+
+impl Pipeline {
+    pub fn ship(&mut self, weeks: u32, amount: u32) {
+        let slot = weeks.max(1) as usize - 1;
+        if self.0.len() <= slot {
+            self.0.resize(slot + 1, 0);
+        }
+        self.0[slot] += amount;
+    }
+
+    pub fn receive(&mut self) -> u32 {
+        if self.0.is_empty() { 0 } else { self.0.remove(0) }
+    }
+
+    pub fn in_transit(&self) -> u32 {
+        self.0.iter().sum()
+    }
+}
+
+impl PlayerState {
+    // Orders whatever the customer asked for this week, as both the matching default order and the pass-on bot do
+    pub fn demand_order(&self) -> u32 {
+        self.incoming_request
+    }
+
+    // Warehouses a delivery as a fresh lot
+    pub fn receive(&mut self, amount: u32) {
+        if amount == 0 {
+            return
+        }
+        match self.lots.last_mut() {
+            Some(lot) if lot.age == 0 => lot.amount += amount,
+            _ => self.lots.push(Lot { age: 0, amount }),
+        }
+        self.stock += amount;
+    }
+
+    // Takes units out of stock, oldest lots first
+    pub fn issue(&mut self, amount: u32) {
+        let mut remaining = amount;
+        for lot in self.lots.iter_mut() {
+            let taken = remaining.min(lot.amount);
+            lot.amount -= taken;
+            remaining -= taken;
+        }
+        self.lots.retain(|l| l.amount > 0);
+        self.stock -= amount;
+    }
+
+    // Ages every lot by a week and writes off anything past its shelf life, returning the amount spoiled
+    pub fn age_stock(&mut self, shelf_life: Option<u32>) -> u32 {
+        for lot in self.lots.iter_mut() {
+            lot.age += 1;
+        }
+        let Some(weeks) = shelf_life else { return 0 };
+        let spoiled = self.lots.iter().filter(|l| l.age >= weeks).map(|l| l.amount).sum();
+        self.lots.retain(|l| l.age < weeks);
+        self.stock -= spoiled;
+        spoiled
+    }
+}
+
+impl Default for Demand {
+    fn default() -> Self {
+        Demand::Constant(1)
+    }
+}
+
+impl Demand {
+    pub fn at(&self, week: u32) -> u32 {
+        match self {
+            Demand::Constant(amount) => *amount,
+            Demand::Step { initial, stepped, week: step_week } => if week < *step_week { *initial } else { *stepped },
+        }
+    }
+}
+
+impl Allocation {
+    // Splits what is available over the open orders, given oldest first by what each is still owed
+    pub fn allocate(&self, owed: &[u32], available: u32) -> Vec<u32> {
+        let total: u32 = owed.iter().sum();
+        if available >= total {
+            return owed.to_vec()
+        }
+
+        let mut shares = vec![0; owed.len()];
+        let mut left = available;
+        match self {
+            Allocation::Fifo | Allocation::Priority => {
+                let mut order: Vec<usize> = (0..owed.len()).collect();
+                if *self == Allocation::Priority {
+                    order.rotate_right(1);
+                }
+                for i in order {
+                    shares[i] = owed[i].min(left);
+                    left -= shares[i];
+                }
+            },
+            Allocation::Proportional => {
+                for (share, o) in shares.iter_mut().zip(owed) {
+                    *share = (*o as u64 * available as u64 / total as u64) as u32;
+                    left -= *share;
+                }
+                // Whatever rounding leaves over goes to the oldest orders
+                for (share, o) in shares.iter_mut().zip(owed) {
+                    let extra = (o - *share).min(left);
+                    *share += extra;
+                    left -= extra;
+                }
+            },
+            Allocation::FairShare => {
+                while left > 0 {
+                    let open = shares.iter().zip(owed).filter(|(s, o)| s < o).count() as u32;
+                    let each = (left / open).max(1);
+                    for (share, o) in shares.iter_mut().zip(owed) {
+                        let extra = (o - *share).min(each).min(left);
+                        *share += extra;
+                        left -= extra;
+                    }
+                }
+            },
+        }
+        shares
+    }
+}
+
+impl BotPolicy {
+    pub fn order(&self, player: &PlayerState) -> u32 {
+        match self {
+            BotPolicy::BaseStock { target } => {
+                let position = player.stock + player.incoming + player.expedited.in_transit();
+                player.incoming_request + (target + player.deficit).saturating_sub(position)
+            },
+            BotPolicy::PassOn => player.demand_order(),
+        }
+    }
+}
+
+impl ProfitSettings {
+    pub fn price(&self, role: PlayerRole) -> u32 {
+        self.prices.get(&role).copied().unwrap_or(0)
+    }
+}
+
+impl Default for ProductionSettings {
+    fn default() -> Self {
+        ProductionSettings {
+            lead_time: 1,
+            yield_percent: 100,
+            seed: 0,
+            batch_size: 0,
+            capacity: 0,
+        }
+    }
+}
+
+impl ProductionSettings {
+    // Rounds the order up to whole batches, then caps it at capacity
+    pub fn schedule(&self, ordered: u32) -> u32 {
+        let mut scheduled = match self.batch_size {
+            0 => ordered,
+            batch => ordered.div_ceil(batch) * batch,
+        };
+        if self.capacity > 0 {
+            scheduled = scheduled.min(self.capacity);
+        }
+        scheduled
+    }
+
+    // The same seed and week always lose the same units. Drawn in one go, so a huge order costs no more than a small one
+    pub fn successful(&self, scheduled: u32, week: u32) -> u32 {
+        if self.yield_percent >= 100 {
+            return scheduled
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(week as u64));
+        let chance = self.yield_percent as f64 / 100.0;
+        Binomial::new(scheduled as u64, chance).unwrap().sample(&mut rng) as u32
+    }
+}
+
+impl GameSettings {
+    // Settings for running one of the further products through the engine on its own
+    pub fn for_product(&self, index: usize) -> GameSettings {
+        let product = &self.products[index];
+        GameSettings {
+            initial_request: product.initial_request,
+            stock_cost: product.stock_cost,
+            deficit_cost: product.deficit_cost,
+            demand: product.demand.clone(),
+            shelf_life: product.shelf_life,
+            spoilage_cost: product.spoilage_cost,
+            products: vec![],
+            expedite: None,
+            alternative_suppliers: vec![],
+            // Prices and cash are only kept for the first product
+            profit: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_active(&self, role: PlayerRole) -> bool {
+        self.chain.contains(&role)
+    }
+
+    // The chain must keep the usual ordering of tiers, with any number of them removed
+    pub fn has_valid_chain(&self) -> bool {
+        let positions: Vec<usize> = self.chain.iter()
+            .map(|role| PlayerRole::ROLES.iter().position(|r| r == role).unwrap())
+            .collect();
+        !positions.is_empty() && positions.windows(2).all(|w| w[0] < w[1])
+    }
+
+    pub fn supplier_of(&self, role: PlayerRole) -> Option<PlayerRole> {
+        let position = self.chain.iter().position(|r| *r == role)?;
+        self.chain.get(position + 1).copied()
+    }
+
+    pub fn customer_of(&self, role: PlayerRole) -> Option<PlayerRole> {
+        let position = self.chain.iter().position(|r| *r == role)?;
+        self.chain.get(position.checked_sub(1)?).copied()
+    }
+
+    // What a tier pays per unit delivered by its regular supplier in profit mode
+    pub fn purchase_price(&self, role: PlayerRole) -> u32 {
+        match (&self.profit, self.supplier_of(role)) {
+            (Some(profit), Some(supplier)) => self.contracts.get(&role)
+                .and_then(|c| c.wholesale_price)
+                .unwrap_or(profit.price(supplier)),
+            (Some(profit), None) => profit.production_price,
+            (None, _) => 0,
+        }
+    }
+
+    // What a tier earns per unit shipped in profit mode, which is whatever its customer pays
+    pub fn sale_price(&self, role: PlayerRole) -> u32 {
+        match (&self.profit, self.customer_of(role)) {
+            (Some(_), Some(customer)) => self.purchase_price(customer),
+            (Some(profit), None) => profit.price(role),
+            (None, _) => 0,
+        }
+    }
+
+    // The role whose seat is allowed to place orders for the given role
+    pub fn ordering_role(&self, role: PlayerRole) -> PlayerRole {
+        match self.supplier_of(role) {
+            Some(supplier) if self.vendor_managed.contains(&role) => supplier,
+            _ => role,
+        }
+    }
+
+    // All roles a seat places orders for, starting with its own if it still orders for itself
+    pub fn roles_ordered_by(&self, seat: PlayerRole) -> Vec<PlayerRole> {
+        self.chain.iter().copied().filter(|r| self.ordering_role(*r) == seat).collect()
+    }
+
+    // The policy a role's orders are placed by when its orders come from a bot seat
+    pub fn bot_for(&self, role: PlayerRole) -> Option<BotPolicy> {
+        self.bots.get(&self.ordering_role(role)).copied()
+    }
+}
+
+impl PlayerRequest {
+    pub fn submitter(&self) -> PlayerRole {
+        self.submitted_by.unwrap_or(self.role)
+    }
+
+    pub fn validate(&self, settings: &GameSettings) -> Result<(), RequestError> {
+        if !settings.is_active(self.role) {
+            return Err(RequestError::NotInChain(self.role))
+        }
+        if settings.ordering_role(self.role) != self.submitter() {
+            return Err(RequestError::NotAuthorised { submitter: self.submitter(), role: self.role })
+        }
+        if self.withdraw {
+            return Ok(())
+        }
+        if self.amount > 0 && self.amount < settings.min_order {
+            return Err(RequestError::BelowMinimumOrder { amount: self.amount, minimum: settings.min_order })
+        }
+        if settings.order_multiple > 1 && !self.amount.is_multiple_of(settings.order_multiple) {
+            return Err(RequestError::NotOrderMultiple { amount: self.amount, multiple: settings.order_multiple })
+        }
+        if self.expedite > 0 && settings.expedite.is_none() {
+            return Err(RequestError::ExpediteUnavailable)
+        }
+        if let Some(line) = self.lines.iter().find(|l| l.supplier >= settings.alternative_suppliers.len()) {
+            return Err(RequestError::UnknownSupplier(line.supplier))
+        }
+        if self.product_amounts.len() != settings.products.len() {
+            return Err(RequestError::WrongProductCount { expected: settings.products.len(), received: self.product_amounts.len() })
+        }
+        for i in 0..settings.products.len() {
+            self.for_product(i).validate(&settings.for_product(i))?;
+        }
+        Ok(())
+    }
+
+    // The plain order for one of the further products
+    pub fn for_product(&self, index: usize) -> PlayerRequest {
+        PlayerRequest {
+            game_id: self.game_id,
+            week: self.week,
+            role: self.role,
+            // A withdrawal may not list the further products at all
+            amount: self.product_amounts.get(index).copied().unwrap_or(0),
+            submitted_by: self.submitted_by,
+            expedite: 0,
+            lines: vec![],
+            product_amounts: vec![],
+            withdraw: self.withdraw,
+        }
+    }
+}
+
 impl GameState {
-    pub fn receive_request(&mut self, request: PlayerRequest) {
-        self.players[request.role].outgoing_request = Some(request.amount);
+    // Checks a request against both the settings and where the game has got to
+    pub fn validate_request(&self, request: &PlayerRequest, settings: &GameSettings) -> Result<(), RequestError> {
+        if request.week != self.week {
+            return Err(RequestError::WeekClosed { week: request.week, current: self.week })
+        }
+        request.validate(settings)?;
+        if request.withdraw {
+            return Ok(())
+        }
+        if let Some((minimum, maximum)) = self.flexibility_range(request.role, settings) {
+            if request.amount < minimum || request.amount > maximum {
+                return Err(RequestError::OutsideFlexibility { amount: request.amount, minimum, maximum })
+            }
+        }
+        Ok(())
+    }
+
+    // The orders a contract with quantity flexibility allows this week
+    pub fn flexibility_range(&self, role: PlayerRole, settings: &GameSettings) -> Option<(u32, u32)> {
+        let flexibility = settings.contracts.get(&role)?.quantity_flexibility?;
+        let last = self.players[role].last_order?;
+        // Small orders are measured against the starting order rate instead, so an order of zero can still be raised
+        let band = (last.max(settings.initial_request) * flexibility / 100).max(1);
+        Some((last.saturating_sub(band), last + band))
+    }
+
+    // The order placed for a seat that missed the turn deadline
+    pub fn default_request(&self, game_id: i64, role: PlayerRole, settings: &GameSettings) -> PlayerRequest {
+        let policy = settings.turn_deadline.as_ref().map_or(DefaultOrder::RepeatLast, |d| d.default_order);
+        self.request_by(game_id, role, settings, policy)
+    }
+
+    // The order a bot seat places for a role, if the role's orders come from one
+    pub fn bot_request(&self, game_id: i64, role: PlayerRole, settings: &GameSettings) -> Option<PlayerRequest> {
+        let bot = settings.bot_for(role)?;
+        Some(self.request_by(game_id, role, settings, DefaultOrder::Bot(bot)))
+    }
+
+    fn request_by(&self, game_id: i64, role: PlayerRole, settings: &GameSettings, policy: DefaultOrder) -> PlayerRequest {
+        PlayerRequest {
+            game_id,
+            week: self.week,
+            role,
+            amount: self.default_amount(role, settings, policy),
+            submitted_by: Some(settings.ordering_role(role)),
+            expedite: 0,
+            lines: vec![],
+            product_amounts: self.products.iter().enumerate()
+                .map(|(i, product)| product.default_amount(role, &settings.for_product(i), policy))
+                .collect(),
+            withdraw: false,
+        }
+    }
+
+    fn default_amount(&self, role: PlayerRole, settings: &GameSettings, policy: DefaultOrder) -> u32 {
+        let p = &self.players[role];
+        let amount = match policy {
+            DefaultOrder::RepeatLast => p.last_order.unwrap_or(p.incoming_request),
+            DefaultOrder::MatchDemand => p.demand_order(),
+            DefaultOrder::Bot(bot) => bot.order(p),
+        };
+
+        // Bring the amount within what the settings accept
+        let multiple = settings.order_multiple.max(1);
+        let amount = if amount > 0 { amount.max(settings.min_order).div_ceil(multiple) * multiple } else { 0 };
+        match self.flexibility_range(role, settings) {
+            Some((minimum, maximum)) => amount.clamp(minimum, maximum),
+            None => amount,
+        }
+    }
+
+    pub fn receive_request(&mut self, request: &PlayerRequest, settings: &GameSettings) -> Result<(), RequestError> {
+        self.validate_request(request, settings)?;
+        let player = &mut self.players[request.role];
+        player.outgoing_request = (!request.withdraw).then_some(request.amount);
+        player.ordered_by = (!request.withdraw).then_some(request.submitter());
+        player.expedite_request = request.expedite;
+        player.alternative_orders = vec![0; settings.alternative_suppliers.len()];
+        for line in &request.lines {
+            player.alternative_orders[line.supplier] += line.amount;
+        }
+        for (i, product) in self.products.iter_mut().enumerate() {
+            product.receive_request(&request.for_product(i), &settings.for_product(i))?;
+        }
+        Ok(())
     }
 
     // Unsure how but could make this return a "ready" game state as opposed to a None to enforce type-level correctness
     // Maybe do that at the receive request level
     // Could also just attempt to take a turn and have it fail, but that feels awkward
-    pub fn get_ready_state(&self) -> bool {
-        self.players.iter().all(|r| r.outgoing_request.is_some())
+    pub fn get_ready_state(&self, settings: &GameSettings) -> bool {
+        settings.chain.iter().all(|r| self.players[*r].outgoing_request.is_some())
+            && self.products.iter().enumerate().all(|(i, p)| p.get_ready_state(&settings.for_product(i)))
+    }
+
+    // Costs are only ever charged to tiers in the chain, so totals stay comparable when tiers are bypassed
+    pub fn total_costs(&self) -> u32 {
+        self.players.iter().map(|p| p.costs).sum::<u32>()
+            + self.products.iter().map(|p| p.total_costs()).sum::<u32>()
+    }
+
+    // A role's costs this week across every product
+    pub fn role_costs(&self, role: PlayerRole) -> u32 {
+        self.players[role].costs + self.products.iter().map(|p| p.role_costs(role)).sum::<u32>()
     }
 
+    // A role's emissions this week across every product
+    pub fn role_emissions(&self, role: PlayerRole) -> u32 {
+        self.players[role].emissions.total() + self.products.iter().map(|p| p.role_emissions(role)).sum::<u32>()
+    }
+
+    // A role's stock across every product
+    pub fn role_stock(&self, role: PlayerRole) -> u32 {
+        self.players[role].stock + self.products.iter().map(|p| p.role_stock(role)).sum::<u32>()
+    }
+
+    // Only the tiers in the settings' chain take part, bypassed tiers are left untouched
     pub fn take_turn(self, settings: &GameSettings) -> GameState {
 
         let mut state = self;
+        let chain = &settings.chain;
 
-        // Warehouse incoming stock
-        for p in state.players.iter_mut() {
-            p.stock += p.incoming;
+        // Further products take their turn separately
+        let products = std::mem::take(&mut state.products);
+
+        // Age what is already on the shelves, then warehouse incoming stock, regular, expedited and from
+        // alternative suppliers
+        for role in chain {
+            let p = &mut state.players[*role];
+            p.spoiled = p.age_stock(settings.shelf_life);
+            let from_supplier = p.incoming + p.expedited.receive();
+            let from_alternatives = p.alternative_inbound.iter_mut().map(|i| i.receive()).sum::<u32>();
+            p.receive(from_supplier + from_alternatives);
+            // Deliveries from the regular supplier are paid for on receipt
+            p.received = from_supplier;
+            p.purchases = from_supplier * settings.purchase_price(*role);
+        }
+
+        // Alternative suppliers have stock on hand, so whatever was ordered from them is shipped straight away
+        for role in chain {
+            let p = &mut state.players[*role];
+            p.alternative_inbound.resize_with(settings.alternative_suppliers.len(), Pipeline::default);
+            for (i, supplier) in settings.alternative_suppliers.iter().enumerate() {
+                let amount = p.alternative_orders.get(i).copied().unwrap_or(0);
+                p.alternative_inbound[i].ship(supplier.lead_time, amount);
+            }
         }
 
         // Move player's outgoing stock to the next player
-        // First is the manufacturer receiving from production queue
-        let mut carried_stock = state.production;
-        for p in state.players.iter_mut().rev() {
+        // First is the most upstream tier receiving from production queue
+        let mut carried_stock = state.production.receive();
+        for role in chain.iter().rev() {
+            let p = &mut state.players[*role];
             p.incoming = carried_stock;
             carried_stock = p.outgoing;
         }
 
-        // Handle the manufacturers production queue
-        state.players[PlayerRole::Manufacturer].incoming = state.production;
-        state.production = state.players[PlayerRole::Manufacturer].outgoing_request.unwrap();
+        // Handle the production queue, fed by whoever is at the top of the chain
+        let ordered = state.players[*chain.last().unwrap()].outgoing_request.unwrap();
+        let scheduled = settings.production.schedule(ordered);
+        let produced = settings.production.successful(scheduled, state.week);
+        state.production.ship(settings.production.lead_time, produced);
+        state.production_scheduled = scheduled;
+        state.production_lost = scheduled - produced;
 
         // Propagate requests
         // Generate request for the first player
-        let customer_request = 1;
+        let customer_request = settings.demand.at(state.week);
         let mut carried_request = customer_request;
-        for p in state.players.iter_mut() {
+        for role in chain {
+            let p = &mut state.players[*role];
             p.incoming_request = carried_request;
             carried_request = p.outgoing_request.unwrap();
         }
 
-        // Send out requested goods and calculate any deficit
-        for p in state.players.iter_mut() {
-            let mut to_send = p.deficit + p.incoming_request;
-            if to_send > p.stock {
-                p.deficit = to_send - p.stock;
-                to_send = p.stock;
+        // Expedited orders ship ahead of everything else. They are filled from the supplier's stock as far as
+        // possible and anything left over is dropped rather than backordered. The top of the chain expedites production,
+        // which is a rush order the factory fills in full, outside its batches and capacity and without yield losses
+        if let Some(expedite) = &settings.expedite {
+            for (i, role) in chain.iter().enumerate() {
+                let requested = state.players[*role].expedite_request;
+                let filled = match chain.get(i + 1) {
+                    Some(supplier) => {
+                        let s = &mut state.players[*supplier];
+                        let filled = requested.min(s.stock);
+                        s.issue(filled);
+                        filled
+                    },
+                    None => requested,
+                };
+                let p = &mut state.players[*role];
+                p.expedite_filled = filled;
+                p.expedited.ship(expedite.lead_time, filled);
             }
-            p.stock -= to_send;
+        }
+
+        // Send out requested goods, split over the customer's open orders, and calculate any deficit
+        for role in chain {
+            // Units issued to the customer's expedited orders are sold as well
+            let expedited = settings.customer_of(*role).map_or(0, |c| state.players[c].expedite_filled);
+            let p = &mut state.players[*role];
+            p.orders.retain(|o| o.filled < o.amount);
+            // Games saved before orders were tracked only carry the deficit
+            if p.orders.is_empty() && p.deficit > 0 {
+                p.orders.push(CustomerOrder { week: state.week - 1, amount: p.deficit, ..Default::default() });
+            }
+            p.orders.push(CustomerOrder { week: state.week, amount: p.incoming_request, ..Default::default() });
+
+            let owed: Vec<u32> = p.orders.iter().map(|o| o.amount - o.filled).collect();
+            let to_send = owed.iter().sum::<u32>().min(p.stock);
+            for (order, shipped) in p.orders.iter_mut().zip(settings.allocation.allocate(&owed, to_send)) {
+                order.shipped = shipped;
+                order.filled += shipped;
+            }
+            p.deficit = p.orders.iter().map(|o| o.amount - o.filled).sum();
+            p.issue(to_send);
             p.outgoing = to_send;
+            p.revenue = (to_send + expedited) * settings.sale_price(*role);
+        }
+
+        // Calculate costs, including the cost of this week's order
+        let top = *chain.last().unwrap();
+        for role in chain {
+            // Expedited units travel the same link as the rest, and those the top of the chain expedites are produced
+            let expedited = settings.customer_of(*role).map_or(0, |c| state.players[c].expedite_filled);
+            let p = &mut state.players[*role];
+            let factors = &settings.emissions;
+            p.emissions = EmissionLedger {
+                transport: (p.outgoing + expedited) * factors.transport.get(role).copied().unwrap_or(0),
+                storage: p.stock * factors.storage,
+                production: if *role == top { (state.production_scheduled + p.expedite_filled) * factors.production } else { 0 },
+                expediting: p.expedite_filled * factors.expediting,
+            };
+
+            let ordered = p.outgoing_request.unwrap();
+            let expedite_premium = settings.expedite.as_ref().map_or(0, |e| e.unit_cost);
+            let alternative_purchasing: u32 = settings.alternative_suppliers.iter()
+                .zip(&p.alternative_orders)
+                .map(|(supplier, amount)| supplier.unit_cost * amount)
+                .sum();
+            // In profit mode the regular supplier is paid through purchases instead
+            let unit_cost = if settings.profit.is_some() { 0 } else { settings.unit_cost };
+            let placed_order = ordered > 0 || p.expedite_request > 0 || p.alternative_orders.iter().any(|a| *a > 0);
+            p.ledger = CostLedger {
+                holding: p.stock * settings.stock_cost,
+                backlog: p.deficit * settings.deficit_cost,
+                ordering: if placed_order { settings.order_cost } else { 0 },
+                purchasing: (ordered + p.expedite_filled) * unit_cost + alternative_purchasing,
+                expediting: p.expedite_filled * expedite_premium,
+                spoilage: p.spoiled * settings.spoilage_cost,
+                overflow: 0,
+                carbon: p.emissions.total() * factors.carbon_price.unwrap_or(0),
+            };
+            p.costs = p.ledger.total();
         }
 
-        // Calculate costs
+        state.products = products.into_iter().enumerate()
+            .map(|(i, product)| product.take_turn(&settings.for_product(i)))
+            .collect();
+
+        // Stock beyond the shared warehouse space is charged against the first product
+        if settings.storage_capacity > 0 {
+            for role in chain {
+                let overflow = state.role_stock(*role).saturating_sub(settings.storage_capacity);
+                let p = &mut state.players[*role];
+                p.ledger.overflow = overflow * settings.overflow_cost;
+                p.costs = p.ledger.total();
+            }
+        }
+
+        // Settle the week's cash. A tier that runs through its credit is bankrupt and ends the game
+        if let Some(profit) = &settings.profit {
+            for role in chain {
+                let p = &mut state.players[*role];
+                p.revenue_shared = 0;
+                p.buyback = 0;
+                p.contract_payments = 0;
+            }
+
+            // Revenue sharing is settled every week, buyback of unsold stock once the game is over
+            for role in chain {
+                let (Some(contract), Some(supplier)) = (settings.contracts.get(role), settings.supplier_of(*role)) else { continue };
+                let p = &mut state.players[*role];
+                p.revenue_shared = p.revenue * contract.revenue_share / 100;
+                p.contract_payments -= p.revenue_shared as i64;
+                state.players[supplier].contract_payments += p.revenue_shared as i64;
+            }
+
+            for role in chain {
+                let p = &mut state.players[*role];
+                p.cash += p.revenue as i64 - p.purchases as i64 - p.costs as i64 + p.contract_payments;
+                p.bankrupt |= p.cash < -profit.credit_limit;
+            }
+        }
+
+        // Orders are placed fresh every week
         for p in state.players.iter_mut() {
-            p.costs = p.stock * settings.stock_cost 
-                    + p.deficit * settings.deficit_cost;
+            p.last_order = p.outgoing_request;
+            p.outgoing_request = None;
+            p.ordered_by = None;
+            p.expedite_request = 0;
+            p.alternative_orders.clear();
         }
 
         state.week += 1;
-        state.game_end = state.week >= settings.max_weeks;
+        state.game_end = state.week >= settings.max_weeks || state.players.iter().any(|p| p.bankrupt);
+        if state.game_end {
+            state.settle_buyback(settings);
+        }
 
         state
     }
+
+    // Suppliers buy back whatever stock their buyers hold when the game ends, however it ends
+    fn settle_buyback(&mut self, settings: &GameSettings) {
+        if settings.profit.is_none() {
+            return
+        }
+        for role in &settings.chain {
+            let (Some(contract), Some(supplier)) = (settings.contracts.get(role), settings.supplier_of(*role)) else { continue };
+            let p = &mut self.players[*role];
+            p.buyback = p.stock * contract.buyback_price;
+            let payment = p.buyback as i64;
+            p.contract_payments += payment;
+            p.cash += payment;
+            let s = &mut self.players[supplier];
+            s.contract_payments -= payment;
+            s.cash -= payment;
+        }
+    }
 }
 
 impl Game {
-    pub fn new(settings: GameSettings) -> Game {
-        let initial_state = GameState {
+    pub fn new(mut settings: GameSettings) -> Game {
+        let mut initial_state = GameState {
             week: 1,
             game_end: false,
-            players: [PlayerState { 
-                stock: settings.initial_request,
-                deficit: 0,
-                incoming: settings.initial_request,
-                outgoing: settings.initial_request,
-                incoming_request: settings.initial_request,
-                outgoing_request: None,
-                costs: 0,
-            }; 4],
-            production: settings.initial_request,
+            players: Default::default(),
+            production: Pipeline::default(),
+            production_scheduled: 0,
+            production_lost: 0,
+            products: (0..settings.products.len())
+                .map(|i| Game::new(settings.for_product(i)).states.remove(0))
+                .collect(),
         };
 
+        // Start with production already running at the initial rate
+        for week in 1..=settings.production.lead_time.max(1) {
+            initial_state.production.ship(week, settings.initial_request);
+        }
+
+        // Bot seats are taken from the start
+        for role in settings.chain.clone() {
+            if settings.bots.contains_key(&role) {
+                settings.players.insert(role, Some("Bot".to_owned()));
+            }
+        }
+
+        // Bypassed tiers keep an empty state for the whole game
+        for role in &settings.chain {
+            let p = &mut initial_state.players[*role];
+            p.receive(settings.initial_request);
+            p.cash = settings.profit.as_ref().map_or(0, |profit| profit.starting_cash);
+            p.incoming = settings.initial_request;
+            p.outgoing = settings.initial_request;
+            p.incoming_request = settings.initial_request;
+        }
+
         Game {
-            settings: settings,
+            settings,
             states: vec![initial_state],
+            deadline: None,
+            status: GameStatus::Lobby,
+            ready: vec![],
         }
     }
 
@@ -208,15 +1351,541 @@ impl Game {
     }
 
     pub fn take_turn(&mut self) {
-        let state = self.states.last().unwrap().take_turn(&self.settings);
+        let state = self.states.last().unwrap().clone().take_turn(&self.settings);
+        if state.game_end {
+            self.status = GameStatus::Finished;
+        }
         self.states.push(state);
     }
 
+    // Free seats the game has to wait for. A role its vendor orders for can be joined to follow along, but isn't needed
+    pub fn open_seats(&self) -> Vec<PlayerRole> {
+        self.get_available_roles().into_iter()
+            .filter(|r| self.settings.ordering_role(*r) == *r)
+            .collect()
+    }
+
+    // Whether every seat in the chain that places its own orders has been taken
+    pub fn seats_filled(&self) -> bool {
+        self.open_seats().is_empty()
+    }
+
+    // Bots are always ready, as are roles their vendor orders for
+    pub fn is_ready(&self, role: PlayerRole) -> bool {
+        self.ready.contains(&role) || self.settings.bots.contains_key(&role) || self.settings.ordering_role(role) != role
+    }
+
+    pub fn everyone_ready(&self) -> bool {
+        self.settings.chain.iter().all(|r| self.is_ready(*r))
+    }
+
+    pub fn start(&mut self) {
+        self.status = GameStatus::Running;
+        self.ready.clear();
+    }
+
+    // Costs over the whole game so far, summed over every tier
+    pub fn chain_costs(&self) -> u32 {
+        self.states.iter().map(|s| s.total_costs()).sum()
+    }
+
+    // Profit made so far in profit mode, otherwise the negated costs, so higher is always better
+    pub fn score(&self, role: PlayerRole) -> i64 {
+        match &self.settings.profit {
+            Some(profit) => self.states.last().unwrap().players[role].cash - profit.starting_cash,
+            None => -(self.states.iter().map(|s| s.role_costs(role) as i64).sum::<i64>()),
+        }
+    }
+
+    // Emissions over the whole game so far, for one tier or the whole chain
+    pub fn role_emissions(&self, role: PlayerRole) -> u32 {
+        self.states.iter().map(|s| s.role_emissions(role)).sum()
+    }
+
+    pub fn chain_emissions(&self) -> u32 {
+        self.settings.chain.iter().map(|r| self.role_emissions(*r)).sum()
+    }
+
+    // How much a tier amplifies demand: the variance of its orders over the variance of customer demand in
+    // the weeks played. Left out until there is demand that varies to compare with
+    pub fn bullwhip(&self, role: PlayerRole) -> Option<f64> {
+        let customer = *self.settings.chain.first()?;
+        let played = self.states.get(1..)?;
+        let orders: Vec<f64> = played.iter().map(|s| s.players[role].last_order.unwrap_or(0) as f64).collect();
+        let demand: Vec<f64> = played.iter().map(|s| s.players[customer].incoming_request as f64).collect();
+
+        let variance = |values: &[f64]| {
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+        };
+        if played.len() < 2 || variance(&demand) == 0.0 {
+            return None
+        }
+        Some(variance(&orders) / variance(&demand))
+    }
+
+    pub fn team_result(&self, team: u32, game_id: i64) -> TeamResult {
+        let chain = &self.settings.chain;
+        let state = self.states.last().unwrap();
+        TeamResult {
+            team,
+            game_id,
+            week: state.week,
+            finished: state.game_end,
+            players: chain.iter().map(|r| (*r, self.settings.players.get(r).cloned().flatten())).collect(),
+            role_costs: chain.iter().map(|r| (*r, self.states.iter().map(|s| s.role_costs(*r)).sum())).collect(),
+            chain_costs: self.chain_costs(),
+            bullwhip: chain.iter().map(|r| (*r, self.bullwhip(*r))).collect(),
+        }
+    }
+
+    // Profit of the whole chain, which contract payments only move around
+    pub fn channel_profit(&self) -> i64 {
+        self.settings.chain.iter().map(|r| self.score(*r)).sum()
+    }
+
+    // What each contract in the game paid out, for the debrief
+    pub fn contract_summaries(&self) -> Vec<ContractSummary> {
+        let Some(profit) = &self.settings.profit else { return vec![] };
+        let mut summaries = vec![];
+        for buyer in self.settings.chain.iter().copied() {
+            let (Some(_), Some(supplier)) = (self.settings.contracts.get(&buyer), self.settings.supplier_of(buyer)) else { continue };
+            let sum = |f: &dyn Fn(&PlayerState) -> i64| self.states.iter().map(|s| f(&s.players[buyer])).sum::<i64>();
+            summaries.push(ContractSummary {
+                buyer,
+                supplier,
+                wholesale_paid: sum(&|p| p.purchases as i64),
+                list_price_paid: sum(&|p| (p.received * profit.price(supplier)) as i64),
+                revenue_shared: sum(&|p| p.revenue_shared as i64),
+                buyback_paid: sum(&|p| p.buyback as i64),
+                buyer_profit: self.score(buyer),
+                supplier_profit: self.score(supplier),
+            });
+        }
+        summaries
+    }
+
+    // Checks an order sent in by a player, who must hold the seat it is placed from
+    pub fn validate_submission(&self, request: &PlayerRequest) -> Result<(), RequestError> {
+        let state = self.states.last().unwrap();
+        match self.status {
+            GameStatus::Lobby => return Err(RequestError::NotStarted),
+            GameStatus::Paused => return Err(RequestError::Paused),
+            GameStatus::Finished | GameStatus::Archived => return Err(RequestError::GameOver),
+            GameStatus::Running if state.game_end => return Err(RequestError::GameOver),
+            GameStatus::Running => (),
+        }
+        if self.settings.players.get(&request.submitter()).is_none_or(|p| p.is_none()) {
+            return Err(RequestError::SeatEmpty(request.submitter()))
+        }
+        state.validate_request(request, &self.settings)
+    }
+
+    // Stops the game where it is, as if the last week had been played
+    pub fn end_early(&mut self) {
+        let state = self.states.last_mut().unwrap();
+        if !state.game_end {
+            state.game_end = true;
+            state.settle_buyback(&self.settings);
+        }
+        self.status = GameStatus::Finished;
+        self.deadline = None;
+    }
+
+    // Units ordered over the game from the regular supplier followed by each alternative supplier
+    pub fn sourcing(&self, role: PlayerRole) -> Vec<(String, u32)> {
+        let regular = match self.settings.supplier_of(role) {
+            Some(supplier) => format!("{:?}", supplier),
+            None => "Production".to_owned(),
+        };
+        let mut sourcing = vec![(regular, 0)];
+        sourcing.extend(self.settings.alternative_suppliers.iter().map(|s| (s.name.clone(), 0)));
+
+        for state in &self.states {
+            let p = &state.players[role];
+            sourcing[0].1 += p.outgoing_request.unwrap_or(0) + p.expedite_filled;
+            for (i, amount) in p.alternative_orders.iter().enumerate() {
+                sourcing[i + 1].1 += amount;
+            }
+        }
+        sourcing
+    }
+
     pub fn get_available_roles(&self) -> Vec<PlayerRole> {
         let mut roles = vec![];
-        for role in PlayerRole::ROLES {
+        for role in self.settings.chain.iter().copied() {
             if self.settings.players.get(&role).unwrap().is_none() { roles.push(role) };
         }
         roles
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(extra: serde_json::Value) -> GameSettings {
+        let mut base = serde_json::json!({"name": "test", "max_weeks": 10, "initial_request": 4, "stock_cost": 1, "deficit_cost": 2,
+            "players": {"Retailer": null, "Wholesaler": null, "Distributor": null, "Manufacturer": null}});
+        for (key, value) in extra.as_object().unwrap() {
+            base[key] = value.clone();
+        }
+        serde_json::from_value(base).unwrap()
+    }
+
+    #[test]
+    fn short_stock_is_split_by_the_allocation_rule() {
+        let owed = [6, 2, 4];
+        assert_eq!(Allocation::Fifo.allocate(&owed, 7), vec![6, 1, 0]);
+        assert_eq!(Allocation::Priority.allocate(&owed, 7), vec![3, 0, 4]);
+        assert_eq!(Allocation::Proportional.allocate(&owed, 6), vec![3, 1, 2]);
+        assert_eq!(Allocation::FairShare.allocate(&owed, 7), vec![3, 2, 2]);
+    }
+
+    #[test]
+    fn backlog_is_cleared_once_shipped_in_full() {
+        let mut game = Game::new(settings(serde_json::json!({"demand": {"Step": {"initial": 12, "stepped": 0, "week": 2}}})));
+        for _ in 0..6 {
+            for p in game.states.last_mut().unwrap().players.iter_mut() {
+                p.outgoing_request = Some(4);
+            }
+            game.take_turn();
+        }
+
+        let retailer = &game.states.last().unwrap().players[PlayerRole::Retailer];
+        assert_eq!(retailer.deficit, 0);
+        assert_eq!(retailer.outgoing, 0);
+        assert!(retailer.orders.iter().all(|o| o.filled == o.amount));
+    }
+
+    #[test]
+    fn flexibility_allows_raising_an_order_of_zero() {
+        let settings = settings(serde_json::json!({"contracts": {"Retailer": {"quantity_flexibility": 25}}}));
+        let mut state = Game::new(settings.clone()).states.remove(0);
+        state.players[PlayerRole::Retailer].last_order = Some(0);
+
+        let (minimum, maximum) = state.flexibility_range(PlayerRole::Retailer, &settings).unwrap();
+        assert_eq!(minimum, 0);
+        assert!(maximum > 0);
+        assert!(state.default_request(1, PlayerRole::Retailer, &settings).amount <= maximum);
+    }
+
+    #[test]
+    fn game_saved_while_paused_loads_paused() {
+        let mut saved = serde_json::to_value(Game::new(settings(serde_json::json!({})))).unwrap();
+        saved.as_object_mut().unwrap().remove("status");
+        saved["paused"] = serde_json::json!(true);
+
+        let game: Game = serde_json::from_value(saved).unwrap();
+        assert_eq!(game.status, GameStatus::Paused);
+    }
+
+    #[test]
+    fn production_saved_as_one_amount_arrives_next_week() {
+        let mut saved = serde_json::to_value(Game::new(settings(serde_json::json!({})))).unwrap();
+        saved["states"][0]["production"] = serde_json::json!(4);
+
+        let game: Game = serde_json::from_value(saved).unwrap();
+        let mut production = game.states[0].production.clone();
+        assert_eq!(production.receive(), 4);
+        assert_eq!(production.in_transit(), 0);
+    }
+
+    fn profit_settings(extra: serde_json::Value) -> GameSettings {
+        let mut extra = extra;
+        extra["profit"] = serde_json::json!({"prices": {"Retailer": 10, "Wholesaler": 8, "Distributor": 6, "Manufacturer": 4},
+            "production_price": 2, "starting_cash": 0});
+        settings(extra)
+    }
+
+    fn play_week(game: &mut Game, expedites: &[(PlayerRole, u32)]) {
+        for role in PlayerRole::ROLES {
+            let mut request = game.states.last().unwrap().default_request(1, role, &game.settings);
+            request.expedite = expedites.iter().find(|(r, _)| *r == role).map_or(0, |(_, amount)| *amount);
+            game.states.last_mut().unwrap().receive_request(&request, &game.settings).unwrap();
+        }
+        game.take_turn();
+    }
+
+    #[test]
+    fn profit_mode_pays_for_purchases_once() {
+        let mut game = Game::new(profit_settings(serde_json::json!({"unit_cost": 3})));
+        play_week(&mut game, &[]);
+        let retailer = &game.states.last().unwrap().players[PlayerRole::Retailer];
+        assert!(retailer.purchases > 0);
+        assert_eq!(retailer.ledger.purchasing, 0);
+    }
+
+    #[test]
+    fn expedited_units_are_sold_by_the_supplier() {
+        let mut game = Game::new(profit_settings(serde_json::json!({"expedite": {"lead_time": 1, "unit_cost": 1}})));
+        play_week(&mut game, &[(PlayerRole::Retailer, 2)]);
+        let state = game.states.last().unwrap();
+        let wholesaler = &state.players[PlayerRole::Wholesaler];
+        assert_eq!(state.players[PlayerRole::Retailer].expedite_filled, 2);
+        assert_eq!(wholesaler.revenue, (wholesaler.outgoing + 2) * game.settings.sale_price(PlayerRole::Wholesaler));
+    }
+
+    #[test]
+    fn expedited_units_add_to_transport_and_production_emissions() {
+        let mut game = Game::new(settings(serde_json::json!({"demand": {"Constant": 4}, "expedite": {"lead_time": 1, "unit_cost": 0},
+            "emissions": {"transport": {"Retailer": 1, "Wholesaler": 2, "Distributor": 3, "Manufacturer": 4}, "storage": 1, "production": 5, "expediting": 10}})));
+        play_week(&mut game, &[(PlayerRole::Retailer, 2), (PlayerRole::Manufacturer, 3)]);
+
+        let players = &game.states.last().unwrap().players;
+        let ledger = |transport, storage, production, expediting| EmissionLedger { transport, storage, production, expediting };
+        assert_eq!(players[PlayerRole::Retailer].emissions, ledger(4, 4, 0, 20));
+        assert_eq!(players[PlayerRole::Wholesaler].emissions, ledger(12, 2, 0, 0));
+        assert_eq!(players[PlayerRole::Distributor].emissions, ledger(12, 4, 0, 0));
+        assert_eq!(players[PlayerRole::Manufacturer].emissions, ledger(16, 4, 35, 30));
+    }
+
+    #[test]
+    fn withdrawal_without_product_amounts_withdraws_every_product() {
+        let products = serde_json::json!([{"name": "Ale", "initial_request": 2, "stock_cost": 1, "deficit_cost": 2, "demand": {"Constant": 2}}]);
+        let mut game = Game::new(settings(serde_json::json!({"products": products})));
+        let state = game.states.last_mut().unwrap();
+        let mut request = state.default_request(1, PlayerRole::Retailer, &game.settings);
+        request.product_amounts = vec![3];
+        state.receive_request(&request, &game.settings).unwrap();
+
+        request.withdraw = true;
+        request.product_amounts = vec![];
+        state.receive_request(&request, &game.settings).unwrap();
+        assert_eq!(state.players[PlayerRole::Retailer].outgoing_request, None);
+        assert_eq!(state.products[0].players[PlayerRole::Retailer].outgoing_request, None);
+    }
+
+    #[test]
+    fn yield_losses_on_a_huge_order_are_drawn_at_once() {
+        let production: ProductionSettings = serde_json::from_value(serde_json::json!({"yield_percent": 90, "seed": 7})).unwrap();
+        let produced = production.successful(u32::MAX, 3);
+        assert_eq!(produced, production.successful(u32::MAX, 3));
+        assert!(produced > u32::MAX / 100 * 89 && produced < u32::MAX / 100 * 91);
+    }
+
+    #[test]
+    fn a_role_ordered_by_its_vendor_is_not_waited_on() {
+        let mut game = Game::new(settings(serde_json::json!({"vendor_managed": ["Retailer"]})));
+        for role in [PlayerRole::Wholesaler, PlayerRole::Distributor, PlayerRole::Manufacturer] {
+            game.settings.players.insert(role, Some(format!("{:?}", role)));
+            game.ready.push(role);
+        }
+        assert!(game.seats_filled());
+        assert!(game.everyone_ready());
+    }
+
+    #[test]
+    fn only_the_vendor_orders_for_a_managed_role() {
+        let settings = settings(serde_json::json!({"vendor_managed": ["Retailer"]}));
+        let state = Game::new(settings.clone()).states.remove(0);
+        let mut request = state.default_request(1, PlayerRole::Retailer, &settings);
+        assert_eq!(request.submitter(), PlayerRole::Wholesaler);
+        assert_eq!(state.validate_request(&request, &settings), Ok(()));
+
+        request.submitted_by = Some(PlayerRole::Retailer);
+        assert_eq!(state.validate_request(&request, &settings),
+            Err(RequestError::NotAuthorised { submitter: PlayerRole::Retailer, role: PlayerRole::Retailer }));
+        assert_eq!(settings.roles_ordered_by(PlayerRole::Wholesaler), vec![PlayerRole::Retailer, PlayerRole::Wholesaler]);
+    }
+
+    #[test]
+    fn bypassed_tiers_are_skipped_by_shipments_and_orders() {
+        let settings = settings(serde_json::json!({"chain": ["Retailer", "Manufacturer"]}));
+        let mut game = Game::new(settings.clone());
+        assert_eq!(settings.supplier_of(PlayerRole::Retailer), Some(PlayerRole::Manufacturer));
+        assert_eq!(settings.customer_of(PlayerRole::Manufacturer), Some(PlayerRole::Retailer));
+
+        let state = game.states.last_mut().unwrap();
+        let bypassed = state.default_request(1, PlayerRole::Wholesaler, &settings);
+        assert_eq!(state.receive_request(&bypassed, &settings), Err(RequestError::NotInChain(PlayerRole::Wholesaler)));
+        for role in [PlayerRole::Retailer, PlayerRole::Manufacturer] {
+            let request = state.default_request(1, role, &settings);
+            state.receive_request(&request, &settings).unwrap();
+        }
+        assert!(state.get_ready_state(&settings));
+        game.take_turn();
+
+        let players = &game.states.last().unwrap().players;
+        assert_eq!(players[PlayerRole::Retailer].incoming, 4);
+        assert_eq!(players[PlayerRole::Manufacturer].incoming_request, 4);
+        assert_eq!(players[PlayerRole::Wholesaler].stock, 0);
+        assert_eq!(players[PlayerRole::Wholesaler].costs, 0);
+    }
+
+    #[test]
+    fn orders_keep_to_the_minimum_and_multiple_and_pay_the_order_cost() {
+        let mut game = Game::new(settings(serde_json::json!({"min_order": 6, "order_multiple": 4, "order_cost": 5})));
+        let settings = game.settings.clone();
+        let state = game.states.last_mut().unwrap();
+        let mut request = state.default_request(1, PlayerRole::Retailer, &settings);
+        assert_eq!(request.amount, 8);
+
+        request.amount = 3;
+        assert_eq!(state.validate_request(&request, &settings), Err(RequestError::BelowMinimumOrder { amount: 3, minimum: 6 }));
+        request.amount = 6;
+        assert_eq!(state.validate_request(&request, &settings), Err(RequestError::NotOrderMultiple { amount: 6, multiple: 4 }));
+        request.amount = 0;
+        state.receive_request(&request, &settings).unwrap();
+        for role in [PlayerRole::Wholesaler, PlayerRole::Distributor, PlayerRole::Manufacturer] {
+            let request = state.default_request(1, role, &settings);
+            state.receive_request(&request, &settings).unwrap();
+        }
+        game.take_turn();
+
+        let players = &game.states.last().unwrap().players;
+        assert_eq!(players[PlayerRole::Retailer].ledger.ordering, 0);
+        assert_eq!(players[PlayerRole::Wholesaler].ledger.ordering, 5);
+    }
+
+    #[test]
+    fn expedited_units_arrive_early_at_a_premium_and_shortfalls_are_dropped() {
+        let mut game = Game::new(settings(serde_json::json!({"expedite": {"lead_time": 1, "unit_cost": 3}})));
+        play_week(&mut game, &[(PlayerRole::Retailer, 2)]);
+        let retailer = &game.states.last().unwrap().players[PlayerRole::Retailer];
+        assert_eq!(retailer.expedite_filled, 2);
+        assert_eq!(retailer.expedited.in_transit(), 2);
+        assert_eq!(retailer.ledger.expediting, 6);
+
+        play_week(&mut game, &[(PlayerRole::Retailer, 100)]);
+        let state = game.states.last().unwrap();
+        let retailer = &state.players[PlayerRole::Retailer];
+        let wholesaler = &state.players[PlayerRole::Wholesaler];
+        assert!(retailer.expedite_filled < 100);
+        assert_eq!(retailer.expedited.in_transit(), retailer.expedite_filled);
+        assert_eq!((wholesaler.stock, wholesaler.outgoing), (0, 0));
+        assert_eq!(retailer.deficit, 0);
+    }
+
+    #[test]
+    fn alternative_suppliers_deliver_after_their_own_lead_time_at_their_own_price() {
+        let mut game = Game::new(settings(serde_json::json!({"unit_cost": 1,
+            "alternative_suppliers": [{"name": "Spot", "lead_time": 2, "unit_cost": 5}]})));
+        let settings = game.settings.clone();
+        let state = game.states.last_mut().unwrap();
+        let mut request = state.default_request(1, PlayerRole::Retailer, &settings);
+        request.lines = vec![OrderLine { supplier: 1, amount: 3 }];
+        assert_eq!(state.validate_request(&request, &settings), Err(RequestError::UnknownSupplier(1)));
+        request.lines = vec![OrderLine { supplier: 0, amount: 3 }];
+        state.receive_request(&request, &settings).unwrap();
+        for role in [PlayerRole::Wholesaler, PlayerRole::Distributor, PlayerRole::Manufacturer] {
+            let request = state.default_request(1, role, &settings);
+            state.receive_request(&request, &settings).unwrap();
+        }
+        game.take_turn();
+
+        let retailer = &game.states.last().unwrap().players[PlayerRole::Retailer];
+        assert_eq!(retailer.ledger.purchasing, 4 + 3 * 5);
+        assert_eq!(retailer.alternative_inbound[0].in_transit(), 3);
+        play_week(&mut game, &[]);
+        assert_eq!(game.states.last().unwrap().players[PlayerRole::Retailer].alternative_inbound[0].in_transit(), 3);
+        play_week(&mut game, &[]);
+        assert_eq!(game.states.last().unwrap().players[PlayerRole::Retailer].alternative_inbound[0].in_transit(), 0);
+    }
+
+    #[test]
+    fn oldest_lots_are_issued_first_and_spoil_past_their_shelf_life() {
+        let mut player = PlayerState::default();
+        player.receive(3);
+        assert_eq!(player.age_stock(Some(3)), 0);
+        player.receive(2);
+        player.issue(4);
+        assert_eq!(player.lots.iter().map(|l| (l.age, l.amount)).collect::<Vec<_>>(), vec![(0, 1)]);
+        assert_eq!(player.stock, 1);
+
+        let mut game = Game::new(settings(serde_json::json!({"shelf_life": 1, "spoilage_cost": 2})));
+        play_week(&mut game, &[]);
+        let retailer = &game.states.last().unwrap().players[PlayerRole::Retailer];
+        assert_eq!(retailer.spoiled, 4);
+        assert_eq!(retailer.ledger.spoilage, 8);
+    }
+
+    #[test]
+    fn each_product_runs_on_its_own_demand() {
+        let products = serde_json::json!([{"name": "Ale", "initial_request": 2, "stock_cost": 1, "deficit_cost": 2, "demand": {"Constant": 7}}]);
+        let mut game = Game::new(settings(serde_json::json!({"demand": {"Constant": 3}, "products": products})));
+        let settings = game.settings.clone();
+        let state = game.states.last_mut().unwrap();
+        let mut request = state.default_request(1, PlayerRole::Retailer, &settings);
+        assert_eq!(request.product_amounts, vec![2]);
+        request.product_amounts = vec![];
+        assert_eq!(state.validate_request(&request, &settings), Err(RequestError::WrongProductCount { expected: 1, received: 0 }));
+        play_week(&mut game, &[]);
+
+        let state = game.states.last().unwrap();
+        assert_eq!(state.players[PlayerRole::Retailer].incoming_request, 3);
+        assert_eq!(state.products[0].players[PlayerRole::Retailer].incoming_request, 7);
+    }
+
+    #[test]
+    fn missed_deadlines_order_by_the_default_order_rule() {
+        let mut settings = settings(serde_json::json!({}));
+        let mut state = Game::new(settings.clone()).states.remove(0);
+        let retailer = &mut state.players[PlayerRole::Retailer];
+        retailer.incoming_request = 5;
+        retailer.last_order = Some(9);
+
+        let mut amount = |default_order| {
+            settings.turn_deadline = Some(TurnDeadline { seconds: 60, default_order });
+            state.default_request(1, PlayerRole::Retailer, &settings).amount
+        };
+        assert_eq!(amount(DefaultOrder::RepeatLast), 9);
+        assert_eq!(amount(DefaultOrder::MatchDemand), 5);
+        assert_eq!(amount(DefaultOrder::Bot(BotPolicy::BaseStock { target: 10 })), 5 + 2);
+    }
+
+    #[test]
+    fn submissions_are_checked_against_the_game() {
+        let mut game = Game::new(settings(serde_json::json!({"max_weeks": 1})));
+        let mut request = game.states[0].default_request(1, PlayerRole::Retailer, &game.settings);
+        assert_eq!(game.validate_submission(&request), Err(RequestError::NotStarted));
+
+        game.start();
+        assert_eq!(game.validate_submission(&request), Err(RequestError::SeatEmpty(PlayerRole::Retailer)));
+        game.settings.players.insert(PlayerRole::Retailer, Some("Ann".to_owned()));
+        assert_eq!(game.validate_submission(&request), Ok(()));
+        request.week = 2;
+        assert_eq!(game.validate_submission(&request), Err(RequestError::WeekClosed { week: 2, current: 1 }));
+
+        play_week(&mut game, &[]);
+        request.week = 2;
+        assert_eq!(game.validate_submission(&request), Err(RequestError::GameOver));
+    }
+
+    #[test]
+    fn bot_seats_are_taken_and_order_by_their_policy() {
+        let game = Game::new(settings(serde_json::json!({"bots": {"Wholesaler": {"BaseStock": {"target": 12}}, "Manufacturer": "PassOn"}})));
+        assert_eq!(game.open_seats(), vec![PlayerRole::Retailer, PlayerRole::Distributor]);
+        assert!(game.is_ready(PlayerRole::Wholesaler) && !game.is_ready(PlayerRole::Retailer));
+
+        let state = &game.states[0];
+        let order = |role| state.bot_request(1, role, &game.settings).map(|r| r.amount);
+        assert_eq!(order(PlayerRole::Retailer), None);
+        // Stock and incoming make 8 of the target, so the bot orders the 4 it was asked for and 4 more
+        assert_eq!(order(PlayerRole::Wholesaler), Some(8));
+        assert_eq!(order(PlayerRole::Manufacturer), Some(4));
+    }
+
+    #[test]
+    fn one_player_with_bots_in_the_other_seats_closes_the_week() {
+        let mut settings = settings(serde_json::json!({"bots": {"Wholesaler": "PassOn", "Distributor": "PassOn", "Manufacturer": "PassOn"}}));
+        settings.players.insert(PlayerRole::Retailer, Some("Ann".to_owned()));
+        let mut game = Game::new(settings.clone());
+        assert!(game.seats_filled());
+        game.start();
+
+        let request = game.states[0].default_request(1, PlayerRole::Retailer, &settings);
+        game.validate_submission(&request).unwrap();
+        let state = game.states.last_mut().unwrap();
+        state.receive_request(&request, &settings).unwrap();
+        assert!(!state.get_ready_state(&settings));
+        for role in PlayerRole::ROLES {
+            if let Some(bot) = state.bot_request(1, role, &settings) {
+                state.receive_request(&bot, &settings).unwrap();
+            }
+        }
+        assert!(state.get_ready_state(&settings));
+        game.take_turn();
+        assert_eq!(game.get_current_week(), 2);
+    }
+}
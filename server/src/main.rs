@@ -1,12 +1,20 @@
 
-use game::{Game, GameListing, GameSettings, PlayerInfo, PlayerRequest, PlayerRole};
+use game::{FacilitatorView, Game, GameCreated, GameEvent, GameListing, GameSettings, GameStatus, PlayerInfo, PlayerRequest, PlayerRole, RequestError, SeatGrant, SessionCreated, SessionJoin, SessionSeat, SessionSettings, TeamResult};
+
+mod seat;
+use seat::{Facilitator, Seat, SeatKey, SessionHost};
 
 #[macro_use] extern crate rocket;
 use rocket_db_pools::{sqlx::{self}, Connection, Database};
 use rocket::fairing::{self, Fairing, AdHoc, Info, Kind};
-use rocket::{Rocket, Build};
-use rocket::http::Status;
+use rocket::{Rocket, Build, Orbit, State};
+use rocket::http::{ContentType, Status};
 use rocket::serde::json::Json;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::Shutdown;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // CORS
 pub struct CORS;
@@ -23,7 +31,7 @@ impl Fairing for CORS {
     async fn on_response<'r>(&self, request: &'r rocket::Request<'_>, response: &mut rocket::Response<'r>) {
         response.set_header(rocket::http::Header::new("Access-Control-Allow-Origin", "*"));
         response.set_header(rocket::http::Header::new("Access-Control-Allow-Methods", "GET, POST, OPTIONS"));
-        response.set_header(rocket::http::Header::new("Access-Control-Allow-Headers", "Content-Type"));
+        response.set_header(rocket::http::Header::new("Access-Control-Allow-Headers", "Content-Type, Authorization"));
         response.set_header(rocket::http::Header::new("Access-Control-Request-Method", "GET, POST, OPTIONS"));
 
         // Handle preflight OPTIONS requests
@@ -38,33 +46,150 @@ impl Fairing for CORS {
 }
 
 
+// Events from every game go through one channel, and each stream picks out those for its own game
+#[derive(Clone)]
+struct GameEvents(broadcast::Sender<(i64, GameEvent)>);
+
+impl GameEvents {
+    fn notify(&self, id: i64, event: GameEvent) {
+        // Nobody listening is not an error
+        let _ = self.0.send((id, event));
+    }
+}
+
+
 // Database tools
 #[derive(Database)]
 #[database("sqlite_games")]
 struct GamesDB(sqlx::SqlitePool);
 
+const REQUESTS_TABLE: &str =
+    "CREATE TABLE IF NOT EXISTS requests (
+            game_id INTEGER NOT NULL,
+            week    INTEGER NOT NULL,
+            role    INTEGER NOT NULL,
+            product INTEGER NOT NULL DEFAULT 0,
+            amount  INTEGER NOT NULL,
+            submitted_by INTEGER NOT NULL,
+            expedite INTEGER NOT NULL DEFAULT 0,
+            lines   TEXT NOT NULL DEFAULT '[]',
+            revision INTEGER NOT NULL DEFAULT 0,
+            withdrawn INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (game_id) REFERENCES games (id),
+            PRIMARY KEY (game_id, week, role, product, revision)
+        )";
+
+// Databases made by an older version lack columns added since, so they are added on launch. Returns whether it was missing
+async fn add_column(dbi: &sqlx::SqlitePool, table: &str, column: &str, definition: &str) -> bool {
+    let (count,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2")
+        .bind(table)
+        .bind(column)
+        .fetch_one(dbi).await.unwrap();
+    if count == 0 {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(dbi).await.unwrap();
+        println!("Added {}.{} to the games database", table, column);
+    }
+    count == 0
+}
+
+// Creates any missing table and brings tables made by an older version up to date
+async fn create_tables(dbi: &sqlx::SqlitePool) {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS games (
+                id     INTEGER PRIMARY KEY AUTOINCREMENT,
+                state  TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'Running'
+            )"
+        ).execute(dbi).await.unwrap();
+    if add_column(dbi, "games", "status", "TEXT NOT NULL DEFAULT 'Running'").await {
+        // Older games may be paused or over, which only their saved state knows
+        let games = sqlx::query_as::<_, (i64, String)>("SELECT id, state FROM games")
+            .fetch_all(dbi).await.unwrap();
+        for (id, state) in games {
+            match serde_json::from_str::<Game>(&state) {
+                Ok(game) => {
+                    sqlx::query("UPDATE games SET status = $1 WHERE id = $2")
+                        .bind(format!("{:?}", game.status))
+                        .bind(id)
+                        .execute(dbi).await.unwrap();
+                },
+                Err(e) => println!("Game {:?} could not be read: {}", id, e),
+            }
+        }
+    }
+    sqlx::query(REQUESTS_TABLE).execute(dbi).await.unwrap();
+    if add_column(dbi, "requests", "submitted_by", "INTEGER NOT NULL DEFAULT 0").await {
+        // Before vendor-managed inventory every order was placed by its own seat
+        sqlx::query("UPDATE requests SET submitted_by = role").execute(dbi).await.unwrap();
+    }
+    add_column(dbi, "requests", "expedite", "INTEGER NOT NULL DEFAULT 0").await;
+    add_column(dbi, "requests", "lines", "TEXT NOT NULL DEFAULT '[]'").await;
+    add_column(dbi, "requests", "product", "INTEGER NOT NULL DEFAULT 0").await;
+    add_column(dbi, "requests", "revision", "INTEGER NOT NULL DEFAULT 0").await;
+    add_column(dbi, "requests", "withdrawn", "INTEGER NOT NULL DEFAULT 0").await;
+    let (keyed,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM pragma_table_info('requests') WHERE name = 'revision' AND pk > 0")
+        .fetch_one(dbi).await.unwrap();
+    if keyed == 0 {
+        // A primary key can't be altered, so older tables are copied into a new one that has the current key
+        let mut tx = dbi.begin().await.unwrap();
+        sqlx::query("ALTER TABLE requests RENAME TO old_requests").execute(&mut *tx).await.unwrap();
+        sqlx::query(REQUESTS_TABLE).execute(&mut *tx).await.unwrap();
+        sqlx::query("INSERT INTO requests (game_id, week, role, product, amount, submitted_by, expedite, lines, revision, withdrawn)
+                     SELECT game_id, week, role, product, amount, submitted_by, expedite, lines, revision, withdrawn FROM old_requests")
+            .execute(&mut *tx).await.unwrap();
+        sqlx::query("DROP TABLE old_requests").execute(&mut *tx).await.unwrap();
+        tx.commit().await.unwrap();
+        println!("Rebuilt requests in the games database with its current primary key");
+    }
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS seats (
+                game_id INTEGER NOT NULL,
+                role    INTEGER NOT NULL,
+                nonce   TEXT NOT NULL,
+                FOREIGN KEY (game_id) REFERENCES games (id),
+                PRIMARY KEY (game_id, role)
+            )"
+        ).execute(dbi).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS history (
+                game_id INTEGER NOT NULL,
+                at      INTEGER NOT NULL,
+                week    INTEGER NOT NULL,
+                action  TEXT NOT NULL,
+                FOREIGN KEY (game_id) REFERENCES games (id)
+            )"
+        ).execute(dbi).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS facilitators (
+                game_id INTEGER PRIMARY KEY,
+                nonce   TEXT NOT NULL,
+                FOREIGN KEY (game_id) REFERENCES games (id)
+            )"
+        ).execute(dbi).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sessions (
+                id    INTEGER PRIMARY KEY AUTOINCREMENT,
+                name  TEXT NOT NULL,
+                nonce TEXT NOT NULL
+            )"
+        ).execute(dbi).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS session_games (
+                session_id INTEGER NOT NULL,
+                team       INTEGER NOT NULL,
+                game_id    INTEGER NOT NULL,
+                FOREIGN KEY (session_id) REFERENCES sessions (id),
+                FOREIGN KEY (game_id) REFERENCES games (id),
+                PRIMARY KEY (session_id, team)
+            )"
+        ).execute(dbi).await.unwrap();
+}
+
 async fn configure_db(rocket: Rocket<Build>) -> fairing::Result {
     if let Some(db) = GamesDB::fetch(&rocket) {
         // Get the inner type
-        let dbi = &db.0;
-        
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS games (
-                    id    INTEGER PRIMARY KEY AUTOINCREMENT,
-                    state TEXT NOT NULL
-                )"
-            ).execute(dbi).await.unwrap();
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS requests (
-                    game_id INTEGER NOT NULL,
-                    week    INTEGER NOT NULL,
-                    role    INTEGER NOT NULL,
-                    amount  INTEGER NOT NULL,
-                    FOREIGN KEY (game_id) REFERENCES games (id),
-                    PRIMARY KEY (game_id, week, role)
-                )"
-            ).execute(dbi).await.unwrap();
-
+        create_tables(&db.0).await;
         println!("Games database configured");
         Ok(rocket)
     } else {
@@ -72,10 +197,327 @@ async fn configure_db(rocket: Rocket<Build>) -> fairing::Result {
     }
 }
 
+fn error_json(message: impl std::fmt::Display) -> rocket::serde::json::Value {
+    serde_json::json!({ "error": message.to_string() })
+}
+
+// Anything Rocket rejects before a handler runs, such as a malformed body, gets the same JSON error shape
+#[catch(default)]
+fn default_catcher(status: Status, _request: &rocket::Request) -> (Status, rocket::serde::json::Value) {
+    (status, error_json(status.reason().unwrap_or("Request failed")))
+}
+
+// A seat token only counts while it is the latest one handed out for that seat
+async fn seat_is_current(db: &mut sqlx::SqliteConnection, seat: &Seat) -> bool {
+    sqlx::query_as::<_, (String,)>("SELECT nonce FROM seats WHERE game_id = $1 AND role = $2")
+        .bind(seat.game_id)
+        .bind(seat.role as u32)
+        .fetch_optional(db)
+        .await.ok().flatten()
+        .is_some_and(|(nonce,)| nonce == seat.nonce)
+}
+
+async fn facilitator_is_current(db: &mut sqlx::SqliteConnection, facilitator: &Facilitator) -> bool {
+    sqlx::query_as::<_, (String,)>("SELECT nonce FROM facilitators WHERE game_id = $1")
+        .bind(facilitator.game_id)
+        .fetch_optional(db)
+        .await.ok().flatten()
+        .is_some_and(|(nonce,)| nonce == facilitator.nonce)
+}
+
+async fn load_game(db: &mut sqlx::SqliteConnection, id: i64) -> Option<Game> {
+    sqlx::query_as::<_, (String,)>("SELECT state FROM games WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await.ok().flatten()
+        .map(|(state,)| serde_json::from_str(&state).unwrap())
+}
+
+// The status is kept in its own column too, so games can be picked out by it without reading every one
+async fn save_game(db: &mut sqlx::SqliteConnection, id: i64, game: &Game) {
+    sqlx::query("REPLACE INTO games (id, state, status) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(serde_json::to_string(game).unwrap())
+        .bind(format!("{:?}", game.status))
+        .execute(db)
+        .await.ok().unwrap();
+}
+
+// Keeps a record of everything done to a game from outside the normal flow of orders
+async fn record(db: &mut sqlx::SqliteConnection, id: i64, game: &Game, action: String) {
+    println!("Game {:?}: {}", id, action);
+    sqlx::query("INSERT INTO history (game_id, at, week, action) VALUES ($1, $2, $3, $4)")
+        .bind(id)
+        .bind(now() as i64)
+        .bind(game.get_current_week())
+        .bind(action)
+        .execute(db)
+        .await.ok().unwrap();
+}
+
+// The game a facilitator's request is about, provided their token is the current one for it
+async fn facilitated_game(db: &mut sqlx::SqliteConnection, facilitator: &Facilitator, id: i64) -> Result<Game, (Status, rocket::serde::json::Value)> {
+    if facilitator.game_id != id || !facilitator_is_current(&mut *db, facilitator).await {
+        return Err((Status::Forbidden, error_json("Your token is not the facilitator's for this game")))
+    }
+    load_game(db, id).await.ok_or((Status::NotFound, error_json(format!("No game with id {}", id))))
+}
+
+// Opens a seat up for someone else, whose join will make any token for it stop working
+async fn free_seat(db: &mut sqlx::SqliteConnection, events: &GameEvents, id: i64, role: PlayerRole) -> (Status, rocket::serde::json::Value) {
+    let Some(mut game) = load_game(&mut *db, id).await else {
+        return (Status::NotFound, error_json(format!("No game with id {}", id)))
+    };
+    if !game.settings.players.contains_key(&role) {
+        return (Status::BadRequest, error_json(format!("{:?} is not a seat in game {}", role, id)))
+    }
+    if game.settings.bots.contains_key(&role) {
+        return (Status::BadRequest, error_json(format!("The {:?} seat is played by a bot", role)))
+    }
+    let name = game.settings.players.insert(role, None).flatten();
+    game.ready.retain(|r| *r != role);
+    save_game(&mut *db, id, &game).await;
+    record(&mut *db, id, &game, format!("{} left the {:?} seat", name.unwrap_or_default(), role)).await;
+
+    sqlx::query("DELETE FROM seats WHERE game_id = $1 AND role = $2")
+        .bind(id)
+        .bind(role as u32)
+        .execute(&mut *db)
+        .await.ok().unwrap();
+
+    println!("Seat {:?} in game {:?} is free again", role, id);
+    events.notify(id, GameEvent::PlayerLeft { role });
+    (Status::Ok, serde_json::json!(game))
+}
+
+fn request_error_status(e: &RequestError) -> Status {
+    match e {
+        RequestError::NotAuthorised { .. } | RequestError::SeatEmpty(_) => Status::Forbidden,
+        RequestError::WeekClosed { .. } | RequestError::GameOver | RequestError::Paused | RequestError::NotStarted => Status::Conflict,
+        _ => Status::BadRequest,
+    }
+}
+
+// Submitting again for the same week amends the order, so every request is stored as the role's next revision
+async fn insert_request(db: &mut sqlx::SqliteConnection, events: &GameEvents, pr: &PlayerRequest) -> Result<(), sqlx::Error> {
+    let (revision,) = sqlx::query_as::<_, (u32,)>("SELECT COALESCE(MAX(revision) + 1, 0) FROM requests WHERE game_id = $1 AND week = $2 AND role = $3")
+        .bind(pr.game_id)
+        .bind(pr.week)
+        .bind(pr.role as u32)
+        .fetch_one(&mut *db)
+        .await?;
+
+    // One row per product with the first product carrying any extra order lines.
+    // A withdrawal is a single row marking the role as having no order
+    let mut rows = vec![(pr.amount, pr.expedite, serde_json::to_string(&pr.lines).unwrap())];
+    if pr.withdraw {
+        println!("Player {:?} withdrew the order for {:?} in game {:?}", pr.submitter(), pr.role, pr.game_id);
+    } else {
+        println!("Player {:?} requested {:?} for {:?} in game {:?} (revision {})", pr.submitter(), pr.amount, pr.role, pr.game_id, revision);
+        rows.extend(pr.product_amounts.iter().map(|amount| (*amount, 0, "[]".to_owned())));
+    }
+    for (product, (amount, expedite, lines)) in rows.into_iter().enumerate() {
+        let result = sqlx::query("INSERT INTO requests (game_id, week, role, product, amount, submitted_by, expedite, lines, revision, withdrawn) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")
+            .bind(pr.game_id)
+            .bind(pr.week)
+            .bind(pr.role as u32)
+            .bind(product as u32)
+            .bind(amount)
+            .bind(pr.submitter() as u32)
+            .bind(expedite)
+            .bind(lines)
+            .bind(revision)
+            .bind(pr.withdraw)
+            .execute(&mut *db)
+            .await?;
+        println!("{:?}", result);
+    }
+
+    events.notify(pr.game_id, match pr.withdraw {
+        true => GameEvent::OrderWithdrawn { role: pr.role },
+        false => GameEvent::OrderSubmitted { role: pr.role },
+    });
+    Ok(())
+}
+
+// Steps the game forward once every tier in the chain has an order in, with the bots ordering last. Returns whether it did
+async fn advance_if_ready(db: &mut sqlx::SqliteConnection, events: &GameEvents, id: i64, mut game: Game) -> bool {
+    let week = game.get_current_week();
+    place_bot_orders(&mut *db, events, id, &game).await;
+    let requests = committed_requests(&mut *db, id, week).await;
+    if requests.len() != game.settings.chain.len() {
+        return false
+    }
+
+    // Plug in the request values
+    for request in requests {
+        game.states.last_mut().unwrap().receive_request(&request, &game.settings).unwrap();
+    }
+
+    // Step the game forward and give the next turn its own deadline
+    game.take_turn();
+    start_turn_clock(&mut game);
+
+    // Update the game state
+    save_game(&mut *db, id, &game).await;
+
+    println!("Game {:?} took a step to week {:?}", id, week + 1);
+    events.notify(id, GameEvent::WeekAdvanced { week: week + 1 });
+    if game.states.last().unwrap().game_end {
+        events.notify(id, GameEvent::GameEnded);
+    }
+    true
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn start_turn_clock(game: &mut Game) {
+    game.deadline = match &game.settings.turn_deadline {
+        Some(deadline) if game.status == GameStatus::Running => Some(now() + deadline.seconds),
+        _ => None,
+    };
+}
+
+// Fills in default orders for any seat that let a turn deadline pass, then moves the game on
+async fn deadline_ticker(pool: sqlx::SqlitePool, events: GameEvents) {
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut conn = match pool.acquire().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Could not check turn deadlines: {}", e);
+                continue
+            },
+        };
+        let games = match sqlx::query_as::<_, (i64, String)>("SELECT id, state FROM games WHERE status = 'Running'")
+            .fetch_all(&mut *conn)
+            .await {
+            Ok(games) => games,
+            Err(e) => {
+                println!("Could not check turn deadlines: {}", e);
+                continue
+            },
+        };
+
+        for (id, state) in games {
+            let mut game = match serde_json::from_str::<Game>(&state) {
+                Ok(game) => game,
+                Err(e) => {
+                    println!("Could not read game {:?}: {}", id, e);
+                    continue
+                },
+            };
+            if game.deadline.is_none_or(|deadline| deadline > now()) {
+                continue
+            }
+
+            println!("Deadline passed in game {:?}", id);
+            place_default_orders(&mut conn, &events, id, &game).await;
+            if !advance_if_ready(&mut conn, &events, id, game.clone()).await {
+                // Some order could not be placed by default, so the week waits on its players rather than retrying every second
+                game.deadline = None;
+                save_game(&mut conn, id, &game).await;
+                record(&mut conn, id, &game, "Turn deadline dropped as a default order could not be placed".to_owned()).await;
+            }
+        }
+    }
+}
+
+// Orders on behalf of every seat that has not ordered this week. Bots are left to order for themselves
+async fn place_default_orders(db: &mut sqlx::SqliteConnection, events: &GameEvents, id: i64, game: &Game) {
+    let state = game.states.last().unwrap();
+    let committed = committed_requests(&mut *db, id, state.week).await;
+    let missing = game.settings.chain.iter().copied()
+        .filter(|r| !committed.iter().any(|c| c.role == *r) && game.settings.bot_for(*r).is_none());
+    for role in missing {
+        let request = state.default_request(id, role, &game.settings);
+        match state.validate_request(&request, &game.settings) {
+            Ok(()) => {
+                println!("Ordering {:?} for {:?} in game {:?} by default", request.amount, role, id);
+                insert_request(&mut *db, events, &request).await.unwrap();
+            },
+            Err(e) => println!("Could not place a default order for {:?} in game {:?}: {}", role, id, e),
+        }
+    }
+}
+
+// Has the bots order once every human has, so they see the same week the humans did
+async fn place_bot_orders(db: &mut sqlx::SqliteConnection, events: &GameEvents, id: i64, game: &Game) {
+    let state = game.states.last().unwrap();
+    if state.game_end {
+        return
+    }
+    let committed = committed_requests(&mut *db, id, state.week).await;
+    let (bots, humans): (Vec<PlayerRole>, Vec<PlayerRole>) = game.settings.chain.iter().copied()
+        .filter(|r| !committed.iter().any(|c| c.role == *r))
+        .partition(|r| game.settings.bot_for(*r).is_some());
+    if !humans.is_empty() {
+        return
+    }
+
+    for role in bots {
+        let request = state.bot_request(id, role, &game.settings).unwrap();
+        match state.validate_request(&request, &game.settings) {
+            Ok(()) => {
+                println!("Bot ordering {:?} for {:?} in game {:?}", request.amount, role, id);
+                insert_request(&mut *db, events, &request).await.unwrap();
+            },
+            Err(e) => println!("Bot could not order for {:?} in game {:?}: {}", role, id, e),
+        }
+    }
+}
+
+async fn start_deadline_ticker(rocket: &Rocket<Orbit>) {
+    let pool = GamesDB::fetch(rocket).unwrap().0.clone();
+    let events = rocket.state::<GameEvents>().unwrap().clone();
+    rocket::tokio::spawn(deadline_ticker(pool, events));
+}
+
+// Every amendment is kept as a new revision, the latest one for each role is the order that counts
+async fn committed_requests(db: &mut sqlx::SqliteConnection, game_id: i64, week: u32) -> Vec<PlayerRequest> {
+    let rows = sqlx::query_as::<_, (u32, u32, u32, u32, u32, String, bool)>(
+        "SELECT role, product, amount, submitted_by, expedite, lines, withdrawn FROM requests r
+            WHERE game_id = $1 AND week = $2
+            AND revision = (SELECT MAX(revision) FROM requests WHERE game_id = r.game_id AND week = r.week AND role = r.role)
+            ORDER BY role, product")
+        .bind(game_id)
+        .bind(week)
+        .fetch_all(&mut *db)
+        .await.ok().unwrap();
+
+    // Put each role's rows back together into a single request
+    let mut requests: Vec<PlayerRequest> = vec![];
+    for (role, product, amount, submitted_by, expedite, lines, withdrawn) in rows {
+        if withdrawn {
+            continue
+        }
+        if product == 0 {
+            requests.push(PlayerRequest {
+                game_id,
+                week,
+                role: role.try_into().unwrap(),
+                amount,
+                submitted_by: Some(submitted_by.try_into().unwrap()),
+                expedite,
+                lines: serde_json::from_str(&lines).unwrap(),
+                product_amounts: vec![],
+                withdraw: false,
+            });
+        } else {
+            requests.last_mut().unwrap().product_amounts.push(amount);
+        }
+    }
+    requests
+}
+
 // Requests
 #[get("/games")]
 async fn serve_games(mut db: Connection<GamesDB>) -> (Status, rocket::serde::json::Value) {
-    let result = sqlx::query_as::<_, (i64, String)>("SELECT id, state FROM games")
+    // Archived games stay reachable by id but are no longer listed
+    let result = sqlx::query_as::<_, (i64, String)>("SELECT id, state FROM games WHERE status != 'Archived'")
         .fetch_all(&mut **db)
         .await;
 
@@ -86,6 +528,7 @@ async fn serve_games(mut db: Connection<GamesDB>) -> (Status, rocket::serde::jso
                 GameListing {   id: s.0, 
                                 name: game.settings.name.clone(),
                                 available_roles: game.get_available_roles(),
+                                status: game.status,
                             }
             }).collect();
             (Status::Ok, serde_json::json!(listings))
@@ -124,23 +567,42 @@ async fn serve_gamestate(mut db: Connection<GamesDB>, id: i64) -> (Status, rocke
     }
 }
 
-#[post("/creategame", format="application/json", data="<gs>")]
-async fn create_game(mut db: Connection<GamesDB>, gs: Json<GameSettings>) -> (Status, rocket::serde::json::Value) {
-    // Create a new game with the incoming settings
-    let gs = gs.into_inner();
-    let game = Game::new(gs);   
+// Stores a new game and hands out its facilitator's token
+async fn insert_game(db: &mut sqlx::SqliteConnection, key: &SeatKey, mut gs: GameSettings) -> Result<GameCreated, sqlx::Error> {
+    // Seats are only taken by joining, which hands out their tokens. Bots are seated by the game itself
+    gs.players = PlayerRole::ROLES.iter().map(|role| (*role, None)).collect();
+
+    // Games wait in the lobby, with no clock running, until they are started
+    let game = Game::new(gs);
 
     // Insert game into DB and get the row ID
-    let result = sqlx::query_as::<_, (i64,)>("INSERT INTO games (state) VALUES ($1) RETURNING id")
+    let (id,) = sqlx::query_as::<_, (i64,)>("INSERT INTO games (state, status) VALUES ($1, $2) RETURNING id")
         .bind(serde_json::to_string(&game).unwrap())
-        .fetch_one(&mut **db)
-        .await;
+        .bind(format!("{:?}", game.status))
+        .fetch_one(&mut *db)
+        .await?;
 
-    match result {
-        Ok(v) => {
-            println!("New game {:?} created with id: {:?}", &game.settings.name, v.0);
-            (Status::Created, serde_json::json!(Some(v.0)))
-        },
+    println!("New game {:?} created with id: {:?}", &game.settings.name, id);
+    let (facilitator_token, nonce) = key.issue_facilitator(id);
+    sqlx::query("INSERT INTO facilitators (game_id, nonce) VALUES ($1, $2)")
+        .bind(id)
+        .bind(nonce)
+        .execute(&mut *db)
+        .await?;
+    Ok(GameCreated { id, facilitator_token })
+}
+
+#[post("/creategame", format="application/json", data="<gs>")]
+async fn create_game(mut db: Connection<GamesDB>, key: &State<SeatKey>, gs: Json<GameSettings>) -> (Status, rocket::serde::json::Value) {
+    // Create a new game with the incoming settings
+    let gs = gs.into_inner();
+    if !gs.has_valid_chain() {
+        println!("Refused to create game {:?} with chain {:?}", gs.name, gs.chain);
+        return (Status::BadRequest, serde_json::json!(None::<i64>))
+    }
+
+    match insert_game(&mut db, key, gs).await {
+        Ok(created) => (Status::Created, serde_json::json!(created)),
         Err(e) => {
             println!("Failed to create new game due to error: {:?}", e.to_string());
             (Status::BadRequest, serde_json::json!(None::<i64>))
@@ -149,7 +611,7 @@ async fn create_game(mut db: Connection<GamesDB>, gs: Json<GameSettings>) -> (St
 }
 
 #[post("/joingame/<id>", format="application/json", data="<pi>")]
-async fn join_game(mut db: Connection<GamesDB>, id: i64, pi: Json<PlayerInfo>) -> (Status, rocket::serde::json::Value) {
+async fn join_game(mut db: Connection<GamesDB>, key: &State<SeatKey>, events: &State<GameEvents>, id: i64, pi: Json<PlayerInfo>) -> (Status, rocket::serde::json::Value) {
     let pi = pi.into_inner();
     // Check to see whether that role is still available
     // Not contention safe, but little of this is without breaking everything down into the DB
@@ -166,95 +628,659 @@ async fn join_game(mut db: Connection<GamesDB>, id: i64, pi: Json<PlayerInfo>) -
     }
     let mut game = serde_json::from_str::<Game>(&result.unwrap().0).unwrap();
 
+    if !game.settings.is_active(pi.role) {
+        return (Status::BadRequest, error_json(RequestError::NotInChain(pi.role)))
+    }
     // Check the existing player roles
-    if game.settings.players.get(&pi.role).unwrap().is_none() {
-        // Insert player
-        // Since the hashmap support simply not having an entry for a given key, the Option<String> in there is very overkill
-        game.settings.players.insert(pi.role, Some(pi.name));
-    } else {
+    if game.settings.players.get(&pi.role).unwrap().is_some() {
         return (Status::BadRequest, serde_json::json!(None::<Game>))
     }
+    if matches!(game.status, GameStatus::Finished | GameStatus::Archived) {
+        return (Status::Conflict, error_json(RequestError::GameOver))
+    }
+    let token = take_seat(&mut db, key, events, id, &mut game, pi).await;
+    (Status::Ok, serde_json::json!(SeatGrant { token, game }))
+}
+
+// Puts a player in a free seat and hands out the seat's token, replacing any earlier one
+async fn take_seat(db: &mut sqlx::SqliteConnection, key: &SeatKey, events: &GameEvents, id: i64, game: &mut Game, pi: PlayerInfo) -> String {
+    // Since the hashmap support simply not having an entry for a given key, the Option<String> in there is very overkill
+    game.settings.players.insert(pi.role, Some(pi.name.clone()));
+    save_game(&mut *db, id, game).await;
+
+    let (token, nonce) = key.issue_seat(id, pi.role);
+    sqlx::query("REPLACE INTO seats (game_id, role, nonce) VALUES ($1, $2, $3)")
+    .bind(id)
+    .bind(pi.role as u32)
+    .bind(nonce)
+    .execute(&mut *db)
+    .await.ok().unwrap();
+
+    record(&mut *db, id, game, format!("{} joined as {:?}", pi.name, pi.role)).await;
+    events.notify(id, GameEvent::PlayerJoined { role: pi.role, name: pi.name });
+    token
+}
+
+#[post("/submitrequest", format="application/json", data="<pr>")]
+async fn receive_request(mut db: Connection<GamesDB>, events: &State<GameEvents>, seat: Seat, pr: Json<PlayerRequest>) -> (Status, rocket::serde::json::Value) {
+    let pr = pr.into_inner();
+
+    // Fetch the game first, so an unknown game is reported as such rather than as a seat the token doesn't hold
+    let result = sqlx::query_as::<_, (String,)>("SELECT state FROM games WHERE id = $1")
+    .bind(pr.game_id)
+    .fetch_one(&mut **db)
+    .await;
+
+    let game: Game = match result {
+        Ok(v) => serde_json::from_str(&v.0).unwrap(),
+        Err(_) => return (Status::NotFound, error_json(format!("No game with id {}", pr.game_id)))
+    };
+
+    // Orders can only be placed from the seat the token was issued for
+    if seat.game_id != pr.game_id || seat.role != pr.submitter() || !seat_is_current(&mut db, &seat).await {
+        println!("Rejected request in game {:?}: token does not hold the {:?} seat", pr.game_id, pr.submitter());
+        return (Status::Forbidden, error_json(format!("Your seat token does not hold the {:?} seat in game {}", pr.submitter(), pr.game_id)))
+    }
+
+    if let Err(e) = game.validate_submission(&pr) {
+        println!("Rejected request in game {:?}: {}", pr.game_id, e);
+        return (request_error_status(&e), error_json(e))
+    }
+
+    if let Err(e) = insert_request(&mut db, events, &pr).await {
+        println!("Query failed: {:?}", e);
+        return (Status::BadRequest, error_json("The order could not be stored"))
+    }
+
+    // Check to see if all requests are ready then step the game forward
+    advance_if_ready(&mut db, events, pr.game_id, game).await;
+
+    (Status::Ok, serde_json::json!(pr))
+}
 
-    // Update state in DB
-    sqlx::query("REPLACE INTO games (id, state) VALUES ($1, $2)")
+// Picks a seat back up after the client lost track of it, swapping the token for a fresh one
+#[post("/reclaimseat/<id>")]
+async fn reclaim_seat(mut db: Connection<GamesDB>, key: &State<SeatKey>, seat: Seat, id: i64) -> (Status, rocket::serde::json::Value) {
+    if seat.game_id != id || !seat_is_current(&mut db, &seat).await {
+        return (Status::Forbidden, error_json("Your seat token no longer holds a seat in this game"))
+    }
+    let Some(game) = load_game(&mut db, id).await else {
+        return (Status::NotFound, error_json(format!("No game with id {}", id)))
+    };
+
+    let (token, nonce) = key.issue_seat(id, seat.role);
+    sqlx::query("REPLACE INTO seats (game_id, role, nonce) VALUES ($1, $2, $3)")
     .bind(id)
-    .bind(serde_json::to_string(&game).unwrap())
+    .bind(seat.role as u32)
+    .bind(nonce)
     .execute(&mut **db)
     .await.ok().unwrap();
 
+    println!("Seat {:?} in game {:?} reclaimed", seat.role, id);
+    (Status::Ok, serde_json::json!(SeatGrant { token, game }))
+}
+
+#[post("/leaveseat/<id>")]
+async fn leave_seat(mut db: Connection<GamesDB>, events: &State<GameEvents>, seat: Seat, id: i64) -> (Status, rocket::serde::json::Value) {
+    if seat.game_id != id || !seat_is_current(&mut db, &seat).await {
+        return (Status::Forbidden, error_json("Your seat token no longer holds a seat in this game"))
+    }
+    free_seat(&mut db, events, id, seat.role).await
+}
+
+// Lets the facilitator open up a seat whose player has gone, or kick a player out of it
+#[post("/freeseat/<id>/<role>")]
+async fn facilitator_free_seat(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64, role: u32) -> (Status, rocket::serde::json::Value) {
+    if let Err(e) = facilitated_game(&mut db, &facilitator, id).await {
+        return e
+    }
+    let Ok(role) = role.try_into() else {
+        return (Status::BadRequest, error_json(format!("No role numbered {}", role)))
+    };
+    free_seat(&mut db, events, id, role).await
+}
+
+#[post("/admin/<id>/pause")]
+async fn pause_game(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    if game.status != GameStatus::Running {
+        return (Status::Conflict, error_json("Only a running game can be paused"))
+    }
+    game.status = GameStatus::Paused;
+    game.deadline = None;
+    save_game(&mut db, id, &game).await;
+    record(&mut db, id, &game, "Paused by the facilitator".to_owned()).await;
+    events.notify(id, GameEvent::Paused);
     (Status::Ok, serde_json::json!(game))
 }
 
-#[post("/submitrequest", format="application/json", data="<pr>")]
-async fn receive_request(mut db: Connection<GamesDB>, pr: Json<PlayerRequest>) -> Status {
-    // Submit the request to the DB
-    println!("Player {:?} requested {:?} in game {:?}", pr.role, pr.amount, pr.game_id);
-    let result = sqlx::query("INSERT OR IGNORE INTO requests (game_id, week, role, amount) VALUES ($1, $2, $3, $4)")
-        .bind(pr.game_id)
-        .bind(pr.week)
-        .bind(pr.role as u32)
-        .bind(pr.amount)
+#[post("/admin/<id>/resume")]
+async fn resume_game(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    if game.status != GameStatus::Paused {
+        return (Status::Conflict, error_json("The game is not paused"))
+    }
+    // The turn gets its full time again
+    game.status = GameStatus::Running;
+    start_turn_clock(&mut game);
+    save_game(&mut db, id, &game).await;
+    record(&mut db, id, &game, "Resumed by the facilitator".to_owned()).await;
+    events.notify(id, GameEvent::Resumed);
+    (Status::Ok, serde_json::json!(game))
+}
+
+// Moves the game on without waiting, ordering by default for anyone who has not ordered yet
+#[post("/admin/<id>/advance")]
+async fn force_advance(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    match game.status {
+        GameStatus::Lobby => return (Status::Conflict, error_json(RequestError::NotStarted)),
+        GameStatus::Finished | GameStatus::Archived => return (Status::Conflict, error_json(RequestError::GameOver)),
+        // Players can't order while the game is paused, so every order would be a default one
+        GameStatus::Paused => return (Status::Conflict, error_json(RequestError::Paused)),
+        GameStatus::Running => (),
+    }
+    place_default_orders(&mut db, events, id, &game).await;
+    if !advance_if_ready(&mut db, events, id, game.clone()).await {
+        let committed = committed_requests(&mut db, id, game.get_current_week()).await;
+        let missing: Vec<PlayerRole> = game.settings.chain.iter().copied()
+            .filter(|r| !committed.iter().any(|c| c.role == *r))
+            .collect();
+        return (Status::Conflict, error_json(format!("The week could not close, as no order could be placed for {:?}", missing)))
+    }
+    record(&mut db, id, &game, "Week advanced by the facilitator".to_owned()).await;
+    (Status::Ok, serde_json::json!(load_game(&mut db, id).await))
+}
+
+#[post("/admin/<id>/end")]
+async fn end_game(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    if matches!(game.status, GameStatus::Finished | GameStatus::Archived) {
+        return (Status::Conflict, error_json(RequestError::GameOver))
+    }
+    game.end_early();
+    save_game(&mut db, id, &game).await;
+    record(&mut db, id, &game, "Ended by the facilitator".to_owned()).await;
+    events.notify(id, GameEvent::GameEnded);
+    (Status::Ok, serde_json::json!(game))
+}
+
+// Takes a game out of the lobby, starting the clock on its first turn
+async fn start_game(db: &mut sqlx::SqliteConnection, events: &GameEvents, id: i64, game: &mut Game, by: &str) {
+    game.start();
+    start_turn_clock(game);
+    save_game(&mut *db, id, game).await;
+    record(&mut *db, id, game, format!("Started by {}", by)).await;
+    events.notify(id, GameEvent::Started);
+}
+
+// A player saying they are ready to start. Once every seat is taken and ready the game begins
+#[post("/ready/<id>")]
+async fn ready_seat(mut db: Connection<GamesDB>, events: &State<GameEvents>, seat: Seat, id: i64) -> (Status, rocket::serde::json::Value) {
+    if seat.game_id != id || !seat_is_current(&mut db, &seat).await {
+        return (Status::Forbidden, error_json("Your seat token no longer holds a seat in this game"))
+    }
+    let Some(mut game) = load_game(&mut db, id).await else {
+        return (Status::NotFound, error_json(format!("No game with id {}", id)))
+    };
+    if game.status != GameStatus::Lobby {
+        return (Status::Conflict, error_json("The game has already started"))
+    }
+    if !game.seats_filled() {
+        return (Status::Conflict, error_json(format!("Still waiting for {:?} to be taken", game.open_seats())))
+    }
+
+    if !game.ready.contains(&seat.role) {
+        game.ready.push(seat.role);
+    }
+    events.notify(id, GameEvent::Ready { role: seat.role });
+    match game.everyone_ready() {
+        true => start_game(&mut db, events, id, &mut game, "the players").await,
+        false => save_game(&mut db, id, &game).await,
+    }
+    (Status::Ok, serde_json::json!(game))
+}
+
+// Starts the game without waiting for the players to say they are ready
+#[post("/admin/<id>/start")]
+async fn facilitator_start(mut db: Connection<GamesDB>, events: &State<GameEvents>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    if game.status != GameStatus::Lobby {
+        return (Status::Conflict, error_json("The game has already started"))
+    }
+    if !game.seats_filled() {
+        return (Status::Conflict, error_json(format!("Still waiting for {:?} to be taken", game.open_seats())))
+    }
+    start_game(&mut db, events, id, &mut game, "the facilitator").await;
+    (Status::Ok, serde_json::json!(game))
+}
+
+// Tidies a finished game away from the listing
+#[post("/admin/<id>/archive")]
+async fn archive_game(mut db: Connection<GamesDB>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    if game.status != GameStatus::Finished {
+        return (Status::Conflict, error_json("Only a finished game can be archived"))
+    }
+    game.status = GameStatus::Archived;
+    save_game(&mut db, id, &game).await;
+    record(&mut db, id, &game, "Archived by the facilitator".to_owned()).await;
+    (Status::Ok, serde_json::json!(game))
+}
+
+// Moves a player to an empty seat. Their old token stops working, so the new one is handed back
+// for the facilitator to pass on
+#[post("/admin/<id>/moveseat/<from>/<to>")]
+async fn move_seat(mut db: Connection<GamesDB>, key: &State<SeatKey>, events: &State<GameEvents>, facilitator: Facilitator, id: i64, from: u32, to: u32) -> (Status, rocket::serde::json::Value) {
+    let mut game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    let (Ok(from), Ok(to)) = (PlayerRole::try_from(from), PlayerRole::try_from(to)) else {
+        return (Status::BadRequest, error_json("Unknown role"))
+    };
+    if let Some(role) = [from, to].into_iter().find(|r| !game.settings.is_active(*r)) {
+        return (Status::BadRequest, error_json(RequestError::NotInChain(role)))
+    }
+    if game.settings.bots.contains_key(&from) {
+        return (Status::BadRequest, error_json(format!("The {:?} seat is played by a bot", from)))
+    }
+    let Some(Some(name)) = game.settings.players.get(&from).cloned() else {
+        return (Status::BadRequest, error_json(format!("Nobody is sitting as {:?}", from)))
+    };
+    if !matches!(game.settings.players.get(&to), Some(None)) {
+        return (Status::Conflict, error_json(format!("The {:?} seat is not free", to)))
+    }
+
+    game.settings.players.insert(from, None);
+    game.settings.players.insert(to, Some(name.clone()));
+    save_game(&mut db, id, &game).await;
+
+    let (token, nonce) = key.issue_seat(id, to);
+    sqlx::query("DELETE FROM seats WHERE game_id = $1 AND role = $2")
+        .bind(id)
+        .bind(from as u32)
         .execute(&mut **db)
-        .await;
+        .await.ok().unwrap();
+    sqlx::query("REPLACE INTO seats (game_id, role, nonce) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(to as u32)
+        .bind(nonce)
+        .execute(&mut **db)
+        .await.ok().unwrap();
 
-    match result {
-        Ok(v) => println!("{:?}", v),
-        Err(e) => { 
-            println!("Query failed: {:?}", e);
-            return Status::BadRequest
+    record(&mut db, id, &game, format!("{} moved from {:?} to {:?} by the facilitator", name, from, to)).await;
+    events.notify(id, GameEvent::PlayerLeft { role: from });
+    events.notify(id, GameEvent::PlayerJoined { role: to, name });
+    (Status::Ok, serde_json::json!(SeatGrant { token, game }))
+}
+
+async fn game_history(db: &mut sqlx::SqliteConnection, id: i64) -> Vec<(i64, u32, String)> {
+    sqlx::query_as::<_, (i64, u32, String)>("SELECT at, week, action FROM history WHERE game_id = $1 ORDER BY rowid")
+        .bind(id)
+        .fetch_all(db)
+        .await.ok().unwrap()
+}
+
+#[get("/admin/<id>/history")]
+async fn serve_history(mut db: Connection<GamesDB>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    if let Err(e) = facilitated_game(&mut db, &facilitator, id).await {
+        return e
+    }
+    (Status::Ok, serde_json::json!(game_history(&mut db, id).await))
+}
+
+// Everything the facilitator's dashboard needs in one go
+#[get("/admin/<id>/state")]
+async fn serve_facilitator_view(mut db: Connection<GamesDB>, facilitator: Facilitator, id: i64) -> (Status, rocket::serde::json::Value) {
+    let game = match facilitated_game(&mut db, &facilitator, id).await {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    let submitted = committed_requests(&mut db, id, game.get_current_week()).await
+        .into_iter()
+        .map(|r| r.role)
+        .collect();
+    let history = game_history(&mut db, id).await;
+    (Status::Ok, serde_json::json!(FacilitatorView { game, submitted, history }))
+}
+
+// Streams a game's events as they happen, for as long as the client stays connected
+#[get("/events/<id>")]
+fn serve_events(events: &State<GameEvents>, mut shutdown: Shutdown, id: i64) -> EventStream![] {
+    let mut receiver = events.0.subscribe();
+    EventStream! {
+        loop {
+            let (game_id, event) = select! {
+                message = receiver.recv() => match message {
+                    Ok(message) => message,
+                    Err(RecvError::Closed) => break,
+                    // Clients reload the whole game on any event, so missing a few does no harm
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            if game_id == id {
+                yield Event::json(&event);
+            }
         }
     }
+}
+
+// The order currently committed for a role in the game's current week, if any
+#[get("/committed/<id>/<role>")]
+async fn serve_committed(mut db: Connection<GamesDB>, seat: Seat, id: i64, role: u32) -> (Status, rocket::serde::json::Value) {
+    if seat.game_id != id || !seat_is_current(&mut db, &seat).await {
+        return (Status::Forbidden, error_json("Your seat token is not for this game"))
+    }
 
-    // Check to see if all requests are ready then step the game forward
-    // Get the current game week
     let result = sqlx::query_as::<_, (String,)>("SELECT state FROM games WHERE id = $1")
-    .bind(pr.game_id)
-    .fetch_one(&mut **db)
-    .await.ok().unwrap().0;
+        .bind(id)
+        .fetch_one(&mut **db)
+        .await;
 
-    let mut game: Game = serde_json::from_str(&result).unwrap();
-    let week = game.get_current_week();
+    let game: Game = match result {
+        Ok(v) => serde_json::from_str(&v.0).unwrap(),
+        Err(_) => return (Status::BadRequest, serde_json::json!(None::<PlayerRequest>))
+    };
 
-    // Check we have all four requests for this week
-    let requests = sqlx::query_as::<_, (u32, u32)>("SELECT role, amount FROM requests WHERE game_id = $1 AND week = $2")
-        .bind(pr.game_id)
-        .bind(week)
-        .fetch_all(&mut **db)
+    // Players see their own order and those of any role they order for
+    let committed = committed_requests(&mut db, id, game.get_current_week()).await
+        .into_iter()
+        .find(|r| r.role as u32 == role && game.settings.ordering_role(r.role) == seat.role);
+    (Status::Ok, serde_json::json!(committed))
+}
+
+// Sets up one game per team from the same settings
+#[post("/createsession", format="application/json", data="<ss>")]
+async fn create_session(mut db: Connection<GamesDB>, key: &State<SeatKey>, ss: Json<SessionSettings>) -> (Status, rocket::serde::json::Value) {
+    let ss = ss.into_inner();
+    if ss.teams == 0 || !ss.settings.has_valid_chain() {
+        return (Status::BadRequest, error_json("A session needs at least one team and a valid chain"))
+    }
+
+    let (id,) = sqlx::query_as::<_, (i64,)>("INSERT INTO sessions (name, nonce) VALUES ($1, '') RETURNING id")
+        .bind(&ss.settings.name)
+        .fetch_one(&mut **db)
+        .await.ok().unwrap();
+    let (facilitator_token, nonce) = key.issue_session(id);
+    sqlx::query("UPDATE sessions SET nonce = $1 WHERE id = $2")
+        .bind(nonce)
+        .bind(id)
+        .execute(&mut **db)
         .await.ok().unwrap();
 
-    if requests.len() == 4 {
-        // Plug in the request values
-        for (role, amount) in requests {
-            let role: PlayerRole = role.try_into().unwrap();
-            game.states.last_mut().unwrap().players[role].outgoing_request = Some(amount);
-        }
+    let mut games = vec![];
+    for team in 1..=ss.teams {
+        let mut settings = ss.settings.clone();
+        settings.name = format!("{} team {}", ss.settings.name, team);
+        let created = insert_game(&mut db, key, settings).await.ok().unwrap();
+        sqlx::query("INSERT INTO session_games (session_id, team, game_id) VALUES ($1, $2, $3)")
+            .bind(id)
+            .bind(team)
+            .bind(created.id)
+            .execute(&mut **db)
+            .await.ok().unwrap();
+        games.push(created);
+    }
 
-        // Step the game forward
-        game.take_turn();
+    println!("New session {:?} created with id {:?} and {} teams", ss.settings.name, id, ss.teams);
+    (Status::Created, serde_json::json!(SessionCreated { id, games, facilitator_token }))
+}
 
-        // Update the game state
-        sqlx::query("REPLACE INTO games (id, state) VALUES ($1, $2)")
-        .bind(pr.game_id)
-        .bind(serde_json::to_string(&game).unwrap())
-        .execute(&mut **db)
+// Every team's game in a session, in team order
+async fn session_games(db: &mut sqlx::SqliteConnection, session_id: i64) -> Vec<(u32, i64, Game)> {
+    let rows = sqlx::query_as::<_, (u32, i64, String)>(
+        "SELECT team, game_id, state FROM session_games JOIN games ON games.id = game_id WHERE session_id = $1 ORDER BY team")
+        .bind(session_id)
+        .fetch_all(db)
         .await.ok().unwrap();
+    rows.into_iter().map(|(team, id, state)| (team, id, serde_json::from_str(&state).unwrap())).collect()
+}
 
-        println!("Game {:?} took a step to week {:?}", pr.game_id, week + 1);
+// Seats a student in the emptiest team that is still playing, so every team fills up evenly
+#[post("/session/<id>/join", format="application/json", data="<sj>")]
+async fn join_session(mut db: Connection<GamesDB>, key: &State<SeatKey>, events: &State<GameEvents>, id: i64, sj: Json<SessionJoin>) -> (Status, rocket::serde::json::Value) {
+    let games = session_games(&mut db, id).await;
+    if games.is_empty() {
+        return (Status::NotFound, error_json(format!("No session with id {}", id)))
     }
 
-    Status::Ok
+    let open = games.into_iter()
+        .filter(|(_, _, game)| matches!(game.status, GameStatus::Lobby | GameStatus::Running | GameStatus::Paused) && !game.seats_filled())
+        .max_by_key(|(team, _, game)| (game.open_seats().len(), std::cmp::Reverse(*team)));
+    let Some((team, game_id, mut game)) = open else {
+        return (Status::Conflict, error_json("Every seat in this session is taken"))
+    };
+
+    let role = game.open_seats()[0];
+    println!("Seating {:?} in session {:?} as {:?} of team {}", sj.name, id, role, team);
+    let pi = PlayerInfo { name: sj.into_inner().name, role };
+    let token = take_seat(&mut db, key, events, game_id, &mut game, pi).await;
+    (Status::Ok, serde_json::json!(SessionSeat { game_id, role, token, game }))
+}
+
+async fn session_results(db: &mut sqlx::SqliteConnection, host: &SessionHost, id: i64) -> Result<Vec<TeamResult>, (Status, rocket::serde::json::Value)> {
+    let nonce = sqlx::query_as::<_, (String,)>("SELECT nonce FROM sessions WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await.ok().flatten();
+    if host.session_id != id || nonce.is_none_or(|(nonce,)| nonce != host.nonce) {
+        return Err((Status::Forbidden, error_json("Your token is not the facilitator's for this session")))
+    }
+    Ok(session_games(db, id).await.into_iter().map(|(team, game_id, game)| game.team_result(team, game_id)).collect())
+}
+
+// Every team's costs and bullwhip side by side
+#[get("/session/<id>/results")]
+async fn serve_session_results(mut db: Connection<GamesDB>, host: SessionHost, id: i64) -> (Status, rocket::serde::json::Value) {
+    match session_results(&mut db, &host, id).await {
+        Ok(results) => (Status::Ok, serde_json::json!(results)),
+        Err(e) => e,
+    }
+}
+
+// The same results as a spreadsheet, one row per tier of each team
+#[get("/session/<id>/export")]
+async fn export_session_results(mut db: Connection<GamesDB>, host: SessionHost, id: i64) -> Result<(ContentType, String), (Status, rocket::serde::json::Value)> {
+    let results = session_results(&mut db, &host, id).await?;
+    let mut csv = "team,game_id,week,finished,role,player,costs,bullwhip,chain_costs\n".to_owned();
+    for result in results {
+        for (i, (role, costs)) in result.role_costs.iter().enumerate() {
+            // Names are free text, so they are quoted with any quotes in them doubled
+            let player = format!("\"{}\"", result.players[i].1.clone().unwrap_or_default().replace('"', "\"\""));
+            let bullwhip = result.bullwhip[i].1.map(|b| format!("{:.3}", b)).unwrap_or_default();
+            csv += &format!("{},{},{},{},{:?},{},{},{},{}\n",
+                result.team, result.game_id, result.week, result.finished, role, player, costs, bullwhip, result.chain_costs);
+        }
+    }
+    Ok((ContentType::CSV, csv))
 }
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
+    let rocket = rocket::build();
+    let seat_secret = rocket.figment().extract_inner::<String>("seat_secret").ok();
+    rocket
+        .manage(SeatKey::from_config(seat_secret))
+        .manage(GameEvents(broadcast::channel(1024).0))
         .attach(GamesDB::init())
         .attach(AdHoc::try_on_ignite("DB Configuration", configure_db))
         .attach(CORS)
+        .attach(AdHoc::on_liftoff("Turn deadlines", |rocket| Box::pin(start_deadline_ticker(rocket))))
+        .register("/", catchers![default_catcher])
         .mount("/", routes![serve_games,
                             serve_gameweek,
                             serve_gamestate,
                             create_game,
                             join_game,
-                            receive_request])
+                            receive_request,
+                            serve_committed,
+                            ready_seat,
+                            reclaim_seat,
+                            leave_seat,
+                            facilitator_free_seat,
+                            pause_game,
+                            resume_game,
+                            force_advance,
+                            end_game,
+                            facilitator_start,
+                            archive_game,
+                            move_seat,
+                            serve_history,
+                            serve_facilitator_view,
+                            create_session,
+                            join_session,
+                            serve_session_results,
+                            export_session_results,
+                            serve_events])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_db_pools::sqlx::sqlite::SqlitePoolOptions;
+
+    // Every connection to an in-memory database gets a database of its own, so the pool keeps to one
+    async fn memory_db() -> sqlx::SqlitePool {
+        SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn settings() -> GameSettings {
+        serde_json::from_value(serde_json::json!({"name": "test", "max_weeks": 10, "initial_request": 4, "stock_cost": 1, "deficit_cost": 2,
+            "players": {"Retailer": null, "Wholesaler": null, "Distributor": null, "Manufacturer": null}})).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn requests_from_the_first_version_are_kept_and_rekeyed_by_revision() {
+        let db = memory_db().await;
+        sqlx::query("CREATE TABLE games (id INTEGER PRIMARY KEY AUTOINCREMENT, state TEXT NOT NULL)").execute(&db).await.unwrap();
+        sqlx::query("CREATE TABLE requests (game_id INTEGER NOT NULL, week INTEGER NOT NULL, role INTEGER NOT NULL, amount INTEGER NOT NULL,
+                     FOREIGN KEY (game_id) REFERENCES games (id), PRIMARY KEY (game_id, week, role))").execute(&db).await.unwrap();
+        sqlx::query("INSERT INTO games (id, state) VALUES (1, $1)")
+            .bind(serde_json::to_string(&Game::new(settings())).unwrap())
+            .execute(&db).await.unwrap();
+        sqlx::query("INSERT INTO requests (game_id, week, role, amount) VALUES (1, 1, 2, 7)").execute(&db).await.unwrap();
+
+        create_tables(&db).await;
+        let keys = sqlx::query_as::<_, (String,)>("SELECT name FROM pragma_table_info('requests') WHERE pk > 0 ORDER BY pk")
+            .fetch_all(&db).await.unwrap();
+        assert_eq!(keys.into_iter().map(|(k,)| k).collect::<Vec<_>>(), ["game_id", "week", "role", "product", "revision"]);
+        let row = sqlx::query_as::<_, (i64, i64, i64, i64, bool)>("SELECT amount, submitted_by, product, revision, withdrawn FROM requests")
+            .fetch_one(&db).await.unwrap();
+        assert_eq!(row, (7, 2, 0, 0, false));
+        let (status,) = sqlx::query_as::<_, (String,)>("SELECT status FROM games WHERE id = 1").fetch_one(&db).await.unwrap();
+        assert_eq!(status, "Lobby");
+
+        // A second run finds nothing left to do
+        create_tables(&db).await;
+        sqlx::query("INSERT INTO requests (game_id, week, role, amount, submitted_by, revision) VALUES (1, 1, 2, 5, 2, 1)")
+            .execute(&db).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn only_the_latest_token_for_a_seat_is_current() {
+        let db = memory_db().await;
+        create_tables(&db).await;
+        let key = SeatKey::from_config(Some("secret".to_owned()));
+        let mut conn = db.acquire().await.unwrap();
+        let game = insert_game(&mut conn, &key, settings()).await.unwrap();
+
+        let seat = |nonce: &str| Seat { game_id: game.id, role: PlayerRole::Wholesaler, nonce: nonce.to_owned() };
+        sqlx::query("INSERT INTO seats (game_id, role, nonce) VALUES ($1, 1, 'first')").bind(game.id).execute(&mut *conn).await.unwrap();
+        assert!(seat_is_current(&mut conn, &seat("first")).await);
+        sqlx::query("UPDATE seats SET nonce = 'second' WHERE game_id = $1").bind(game.id).execute(&mut *conn).await.unwrap();
+        assert!(!seat_is_current(&mut conn, &seat("first")).await);
+        assert!(seat_is_current(&mut conn, &seat("second")).await);
+        assert!(!seat_is_current(&mut conn, &Seat { role: PlayerRole::Retailer, ..seat("second") }).await);
+    }
+
+    #[rocket::async_test]
+    async fn orders_and_the_week_they_close_are_pushed_to_watchers() {
+        let db = memory_db().await;
+        create_tables(&db).await;
+        let mut conn = db.acquire().await.unwrap();
+        let id = insert_game(&mut conn, &SeatKey::from_config(Some("secret".to_owned())), settings()).await.unwrap().id;
+        let game = load_game(&mut conn, id).await.unwrap();
+        let events = GameEvents(broadcast::channel(16).0);
+        let mut receiver = events.0.subscribe();
+
+        for role in PlayerRole::ROLES {
+            let request = game.states[0].default_request(id, role, &game.settings);
+            insert_request(&mut conn, &events, &request).await.unwrap();
+        }
+        assert!(advance_if_ready(&mut conn, &events, id, game).await);
+
+        let mut pushed = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            pushed.push(event);
+        }
+        let mut expected: Vec<_> = PlayerRole::ROLES.iter().map(|role| (id, GameEvent::OrderSubmitted { role: *role })).collect();
+        expected.push((id, GameEvent::WeekAdvanced { week: 2 }));
+        assert_eq!(pushed, expected);
+    }
+
+    #[rocket::async_test]
+    async fn the_dashboard_lists_standing_orders_and_what_was_done() {
+        let db = memory_db().await;
+        create_tables(&db).await;
+        let mut conn = db.acquire().await.unwrap();
+        let id = insert_game(&mut conn, &SeatKey::from_config(Some("secret".to_owned())), settings()).await.unwrap().id;
+        let game = load_game(&mut conn, id).await.unwrap();
+        let events = GameEvents(broadcast::channel(16).0);
+
+        for role in [PlayerRole::Retailer, PlayerRole::Wholesaler] {
+            let request = game.states[0].default_request(id, role, &game.settings);
+            insert_request(&mut conn, &events, &request).await.unwrap();
+        }
+        let mut withdrawal = game.states[0].default_request(id, PlayerRole::Wholesaler, &game.settings);
+        withdrawal.withdraw = true;
+        insert_request(&mut conn, &events, &withdrawal).await.unwrap();
+        record(&mut conn, id, &game, "Paused".to_owned()).await;
+
+        let submitted: Vec<_> = committed_requests(&mut conn, id, 1).await.into_iter().map(|r| r.role).collect();
+        assert_eq!(submitted, [PlayerRole::Retailer]);
+        let history = game_history(&mut conn, id).await;
+        assert_eq!(history.iter().map(|(_, week, action)| (*week, action.as_str())).collect::<Vec<_>>(), [(1, "Paused")]);
+    }
+
+    #[rocket::async_test]
+    async fn a_created_game_waits_in_the_lobby_for_its_creator_to_join() {
+        let db = memory_db().await;
+        create_tables(&db).await;
+        let mut conn = db.acquire().await.unwrap();
+        let key = SeatKey::from_config(Some("secret".to_owned()));
+        let mut form = settings();
+        form.players.insert(PlayerRole::Retailer, Some("Left over".to_owned()));
+        let id = insert_game(&mut conn, &key, form).await.unwrap().id;
+
+        let mut game = load_game(&mut conn, id).await.unwrap();
+        assert_eq!(game.status, GameStatus::Lobby);
+        assert_eq!(game.open_seats(), PlayerRole::ROLES);
+        let events = GameEvents(broadcast::channel(16).0);
+        take_seat(&mut conn, &key, &events, id, &mut game, PlayerInfo { name: "Ann".to_owned(), role: PlayerRole::Retailer }).await;
+
+        let game = load_game(&mut conn, id).await.unwrap();
+        assert_eq!(game.open_seats(), [PlayerRole::Wholesaler, PlayerRole::Distributor, PlayerRole::Manufacturer]);
+        let (seats,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM seats WHERE game_id = $1 AND role = 0").bind(id).fetch_one(&mut *conn).await.unwrap();
+        assert_eq!(seats, 1);
+    }
 }
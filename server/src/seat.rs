@@ -0,0 +1,162 @@
+use game::PlayerRole;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::Sha256;
+
+// Secret used to sign seat tokens. Set `seat_secret` in Rocket.toml to keep tokens valid across restarts
+pub struct SeatKey(Vec<u8>);
+
+impl SeatKey {
+    pub fn from_config(secret: Option<String>) -> SeatKey {
+        match secret {
+            Some(secret) => SeatKey(secret.into_bytes()),
+            None => {
+                println!("No seat_secret configured, seat tokens will not survive a restart");
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                SeatKey(key)
+            }
+        }
+    }
+
+    fn sign(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(claims.as_bytes());
+        mac
+    }
+
+    // A token looks like `game.holder.nonce.signature`, where the holder is a role's number, `host` for the
+    // facilitator or `session` for whoever runs a classroom session. The nonce is kept in the database so handing out a new token invalidates the old one
+    fn issue(&self, game_id: i64, holder: &str) -> (String, String) {
+        let nonce = format!("{:016x}", rand::thread_rng().next_u64());
+        let claims = format!("{}.{}.{}", game_id, holder, nonce);
+        let signature = to_hex(&self.sign(&claims).finalize().into_bytes());
+        (format!("{}.{}", claims, signature), nonce)
+    }
+
+    pub fn issue_seat(&self, game_id: i64, role: PlayerRole) -> (String, String) {
+        self.issue(game_id, &(role as u32).to_string())
+    }
+
+    pub fn issue_facilitator(&self, game_id: i64) -> (String, String) {
+        self.issue(game_id, "host")
+    }
+
+    // Session tokens carry the session's id where the others carry a game's
+    pub fn issue_session(&self, session_id: i64) -> (String, String) {
+        self.issue(session_id, "session")
+    }
+
+    fn verify(&self, token: &str) -> Option<(i64, String, String)> {
+        let (claims, signature) = token.rsplit_once('.')?;
+        self.sign(claims).verify_slice(&from_hex(signature)?).ok()?;
+
+        let mut parts = claims.split('.');
+        let game_id = parts.next()?.parse().ok()?;
+        let holder = parts.next()?.to_owned();
+        let nonce = parts.next()?.to_owned();
+        Some((game_id, holder, nonce))
+    }
+}
+
+fn bearer_claims(request: &Request<'_>) -> Result<(i64, String, String), (Status, &'static str)> {
+    let key = request.rocket().state::<SeatKey>().unwrap();
+    let token = request.headers().get_one("Authorization").and_then(|h| h.strip_prefix("Bearer "));
+    match token.map(|t| key.verify(t)) {
+        Some(Some(claims)) => Ok(claims),
+        Some(None) => Err((Status::Unauthorized, "Invalid token")),
+        None => Err((Status::Unauthorized, "Missing token")),
+    }
+}
+
+// The seat a request was made from, taken from its `Authorization: Bearer` header
+pub struct Seat {
+    pub game_id: i64,
+    pub role: PlayerRole,
+    pub nonce: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Seat {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let claims = bearer_claims(request);
+        let role = claims.as_ref().ok().and_then(|(_, holder, _)| holder.parse::<u32>().ok()?.try_into().ok());
+        match (claims, role) {
+            (Ok((game_id, _, nonce)), Some(role)) => Outcome::Success(Seat { game_id, role, nonce }),
+            (Ok(_), None) => Outcome::Error((Status::Forbidden, "Not a seat token")),
+            (Err(e), _) => Outcome::Error(e),
+        }
+    }
+}
+
+// The host of a game, who created it and can manage its seats
+pub struct Facilitator {
+    pub game_id: i64,
+    pub nonce: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Facilitator {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match bearer_claims(request) {
+            Ok((game_id, holder, nonce)) if holder == "host" => Outcome::Success(Facilitator { game_id, nonce }),
+            Ok(_) => Outcome::Error((Status::Forbidden, "Not a facilitator token")),
+            Err(e) => Outcome::Error(e),
+        }
+    }
+}
+
+// Whoever set up a classroom session, who can see and export every team's results
+pub struct SessionHost {
+    pub session_id: i64,
+    pub nonce: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionHost {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match bearer_claims(request) {
+            Ok((session_id, holder, nonce)) if holder == "session" => Outcome::Success(SessionHost { session_id, nonce }),
+            Ok(_) => Outcome::Error((Status::Forbidden, "Not a session token")),
+            Err(e) => Outcome::Error(e),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_tokens_verify_and_altered_ones_do_not() {
+        let key = SeatKey::from_config(Some("secret".to_owned()));
+        let (token, nonce) = key.issue_seat(3, PlayerRole::Distributor);
+        assert_eq!(key.verify(&token), Some((3, "2".to_owned(), nonce)));
+
+        // Claiming another seat breaks the signature
+        let moved = token.replacen("3.2.", "3.0.", 1);
+        assert_eq!(key.verify(&moved), None);
+        assert_eq!(key.verify(&token[..token.len() - 2]), None);
+        assert_eq!(SeatKey::from_config(Some("other".to_owned())).verify(&token), None);
+    }
+}